@@ -0,0 +1,233 @@
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    num_complex::Complex32,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
+
+use crate::sym::MAGNITUDE;
+
+/// Settings of the automatic gain control.
+#[derive(Debug, Clone, Copy, PartialEq, clap::Args)]
+pub struct AgcParams {
+    /// Time constant, in samples, with which the gain is reduced when the
+    /// signal gets stronger.
+    #[arg(
+        long = "agc-attack",
+        default_value_t = 10.0,
+        value_parser = parse_time_constant
+    )]
+    pub attack: f32,
+    /// Time constant, in samples, with which the gain is increased when the
+    /// signal gets weaker.
+    #[arg(
+        long = "agc-decay",
+        default_value_t = 2000.0,
+        value_parser = parse_time_constant
+    )]
+    pub decay: f32,
+    /// Largest gain applied, which stops noise being amplified up to the
+    /// level of a signal when nothing is being received.
+    #[arg(
+        long = "agc-max-gain",
+        default_value_t = 100.0,
+        value_parser = parse_max_gain
+    )]
+    pub max_gain: f32,
+}
+
+impl Default for AgcParams {
+    fn default() -> Self {
+        Self {
+            attack: 10.0,
+            decay: 2000.0,
+            max_gain: 100.0,
+        }
+    }
+}
+
+/// Parse a time constant, which can't be shorter than a sample.
+fn parse_time_constant(s: &str) -> Result<f32, String> {
+    let x: f32 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if x >= 1.0 && x.is_finite() {
+        Ok(x)
+    } else {
+        Err("must be at least one sample".to_string())
+    }
+}
+
+/// Parse the largest gain, which must be positive.
+fn parse_max_gain(s: &str) -> Result<f32, String> {
+    let x: f32 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if x > 0.0 && x.is_finite() {
+        Ok(x)
+    } else {
+        Err("must be positive".to_string())
+    }
+}
+
+/// Automatic gain control.  Follows the envelope of the signal, quickly when
+/// it rises and slowly when it falls, and scales the signal so that the peaks
+/// sit at the magnitude of the constellation points.
+pub struct Agc {
+    attack: f32,
+    decay: f32,
+    min_level: f32,
+    level: f32,
+}
+
+impl Agc {
+    pub fn new(params: AgcParams) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("Agc").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .add_output("out", std::mem::size_of::<Complex32>())
+                .build(),
+            MessageIoBuilder::new().build(),
+            Self::create(params),
+        )
+    }
+
+    fn create(params: AgcParams) -> Self {
+        // Checked when the parameters are parsed.
+        assert!(params.attack >= 1.0 && params.decay >= 1.0 && params.max_gain > 0.0);
+
+        let min_level = MAGNITUDE / params.max_gain;
+
+        Agc {
+            attack: 1.0 / params.attack,
+            decay: 1.0 / params.decay,
+            min_level,
+            level: min_level,
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        MAGNITUDE / self.level
+    }
+
+    fn process(&mut self, x: Complex32) -> Complex32 {
+        let mag = x.norm();
+        let alpha = if mag > self.level {
+            self.attack
+        } else {
+            self.decay
+        };
+
+        self.level = (self.level + (mag - self.level) * alpha).max(self.min_level);
+
+        x * self.gain()
+    }
+}
+
+#[async_trait]
+impl Kernel for Agc {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        _mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let input = sio.input(0).slice::<Complex32>();
+        let output = sio.output(0).slice::<Complex32>();
+        let n = input.len().min(output.len());
+
+        for (i, o) in input.iter().zip(output.iter_mut()) {
+            *o = self.process(*i);
+        }
+
+        if sio.input(0).finished() && n == input.len() {
+            io.finished = true;
+        }
+
+        sio.input(0).consume(n);
+        sio.output(0).produce(n);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futuresdr::num_complex::Complex32;
+
+    use crate::{
+        pulse_shape::PulseShape,
+        qam::QamMod,
+        sym::{Sym, MAGNITUDE},
+    };
+
+    use super::{parse_max_gain, parse_time_constant, Agc, AgcParams};
+
+    /// Largest magnitude in the last quarter of the output for a signal
+    /// scaled by `scale`.
+    fn settled_peak(agc: &mut Agc, scale: f32) -> f32 {
+        let out: Vec<Complex32> = QamMod::modulate(10, PulseShape::default(), &Sym::random(1000))
+            .iter()
+            .map(|x| agc.process(x * scale))
+            .collect();
+
+        out[out.len() * 3 / 4..out.len() - 100]
+            .iter()
+            .map(|x| x.norm())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn normalises_level() {
+        for scale in [0.01, 0.2, 1.0, 5.0] {
+            let mut agc = Agc::create(AgcParams::default());
+
+            assert!((settled_peak(&mut agc, scale) - MAGNITUDE).abs() < 0.02 * MAGNITUDE);
+        }
+    }
+
+    #[test]
+    fn attack_and_decay() {
+        let mut agc = Agc::create(AgcParams::default());
+
+        settled_peak(&mut agc, 0.1);
+
+        // A sudden increase in level is followed within a few samples.
+        for _ in 0..50 {
+            agc.process(Complex32::new(1.0, 0.0));
+        }
+        assert!((agc.process(Complex32::new(1.0, 0.0)).norm() - MAGNITUDE).abs() < 0.01);
+
+        // Whereas the gain only slowly recovers when the signal fades.
+        let faded = agc.process(Complex32::new(0.1, 0.0)).norm();
+        for _ in 0..100 {
+            agc.process(Complex32::new(0.1, 0.0));
+        }
+        assert!(agc.process(Complex32::new(0.1, 0.0)).norm() < 2.0 * faded);
+    }
+
+    #[test]
+    fn max_gain() {
+        let mut agc = Agc::create(AgcParams::default());
+
+        for _ in 0..100_000 {
+            agc.process(Complex32::new(1e-6, 0.0));
+        }
+
+        assert!((agc.gain() - AgcParams::default().max_gain).abs() < 1e-3);
+    }
+
+    #[test]
+    fn bad_params() {
+        assert_eq!(parse_time_constant("10"), Ok(10.0));
+        assert!(parse_time_constant("0.5").is_err());
+        assert!(parse_time_constant("-3").is_err());
+        assert_eq!(parse_max_gain("0.5"), Ok(0.5));
+        assert!(parse_max_gain("0").is_err());
+        assert!(parse_max_gain("inf").is_err());
+        assert!(parse_max_gain("x").is_err());
+    }
+}
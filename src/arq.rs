@@ -0,0 +1,986 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    macros::message_handler,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
+
+use crate::crc::crc16;
+use crate::frame::MAX_FRAME_SZ;
+use crate::header::{Frame, FrameType};
+
+/// Set on data frames that are sent reliably, and so must be acknowledged.
+pub const FLAG_ARQ: u8 = 0x01;
+
+/// Set on frames whose payload ends with an acknowledgement.
+pub const FLAG_ACK: u8 = 0x02;
+
+/// Largest window, limited by the bitmap in an acknowledgement.
+pub const MAX_WINDOW: u16 = 32;
+
+/// Size in bytes of an acknowledgement: the next sequence number expected,
+/// followed by a bitmap of the frames after it that have been received.
+const ACK_LEN: usize = 6;
+
+/// Size in bytes of the destination and source addresses that start every
+/// Ethernet frame.
+const ADDRS_LEN: usize = 12;
+
+/// Number of packets queued for each peer while its window is full, beyond
+/// which further packets are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// Number of reliable frames between other stations that are remembered, so
+/// that their retransmissions are only overheard once.
+const OVERHEARD_LEN: usize = 256;
+
+/// An Ethernet address.
+pub type Mac = [u8; 6];
+
+/// Settings of the link layer ARQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::Args)]
+pub struct ArqParams {
+    /// Acknowledge frames and retransmit those that are lost.  Both ends of
+    /// the link must agree.
+    #[arg(long = "arq")]
+    pub enabled: bool,
+    /// Number of frames that may be sent to a peer ahead of the oldest one
+    /// yet to be acknowledged.
+    #[arg(
+        long = "arq-window",
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u16).range(1..=MAX_WINDOW as i64)
+    )]
+    pub window: u16,
+    /// Number of times a frame is retransmitted before it is given up on.
+    #[arg(long = "arq-retries", default_value_t = 5)]
+    pub retries: u32,
+    /// Time to wait for a frame to be acknowledged before retransmitting it,
+    /// in milliseconds.
+    #[arg(long = "arq-timeout", default_value_t = 500)]
+    pub timeout_ms: u64,
+}
+
+impl Default for ArqParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window: 8,
+            retries: 5,
+            timeout_ms: 500,
+        }
+    }
+}
+
+impl ArqParams {
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// How long a gap in the frames received is waited on before the
+    /// sender must have given up on the missing frame.
+    fn hold(&self) -> Duration {
+        self.timeout() * (self.retries + 1)
+    }
+}
+
+/// Counts of the frames exchanged with a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Frames sent to the peer for the first time.
+    pub sent: u64,
+    pub retransmitted: u64,
+    pub acked: u64,
+    /// Frames given up on after the retry limit.
+    pub failed: u64,
+    /// Packets dropped because the queue for the peer was full.
+    pub overflowed: u64,
+    /// Frames received from the peer.
+    pub received: u64,
+    /// Frames received from the peer more than once.
+    pub duplicates: u64,
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {}, retransmitted {}, acked {}, failed {}, overflowed {}, received {}, duplicates {}",
+            self.sent,
+            self.retransmitted,
+            self.acked,
+            self.failed,
+            self.overflowed,
+            self.received,
+            self.duplicates
+        )
+    }
+}
+
+fn format_mac(mac: &Mac) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Identifies the station with address `mac` in the header of the frames it
+/// sends.
+fn station_id(mac: &Mac) -> u16 {
+    crc16(mac)
+}
+
+/// Whether sequence number `a` comes before `b`.
+fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+/// A frame sent to a peer that hasn't been acknowledged yet.
+struct Unacked {
+    seq: u16,
+    /// The frame as first sent, which is retransmitted unchanged so that the
+    /// receiver can combine the two.
+    frame: Frame,
+    retries: u32,
+    deadline: Instant,
+    /// Acknowledged, or given up on, but still holding back the window
+    /// behind an older frame.
+    done: bool,
+}
+
+/// Reordering of the frames received from a peer.
+struct Receiver {
+    /// Sequence number of the next frame to be delivered.
+    expected: u16,
+    /// Frames received after a missing one, by sequence number.
+    buffer: HashMap<u16, Vec<u8>>,
+    /// When the frame at `expected` was first found to be missing.
+    gap_since: Option<Instant>,
+    ack_due: bool,
+}
+
+impl Receiver {
+    fn ack(&mut self) -> [u8; ACK_LEN] {
+        let bitmap = (0..MAX_WINDOW)
+            .filter(|i| {
+                let seq = self.expected.wrapping_add(i + 1);
+                self.buffer.contains_key(&seq)
+            })
+            .fold(0u32, |bitmap, i| bitmap | 1 << i);
+        let mut ret = [0; ACK_LEN];
+
+        ret[..2].copy_from_slice(&self.expected.to_be_bytes());
+        ret[2..].copy_from_slice(&bitmap.to_be_bytes());
+        self.ack_due = false;
+
+        ret
+    }
+
+    /// Deliver the frames that are now in order.
+    fn deliver(&mut self, packets: &mut Vec<Vec<u8>>, now: Instant) {
+        let mut delivered = false;
+
+        while let Some(packet) = self.buffer.remove(&self.expected) {
+            packets.push(packet);
+            self.expected = self.expected.wrapping_add(1);
+            delivered = true;
+        }
+
+        if self.buffer.is_empty() {
+            self.gap_since = None;
+        } else if delivered || self.gap_since.is_none() {
+            self.gap_since = Some(now);
+        }
+    }
+
+    /// Give up on the missing frames, delivering everything buffered after
+    /// them.
+    fn skip_gap(&mut self, packets: &mut Vec<Vec<u8>>, now: Instant) {
+        if let Some(seq) = self
+            .buffer
+            .keys()
+            .min_by_key(|seq| seq.wrapping_sub(self.expected))
+        {
+            self.expected = *seq;
+        }
+
+        self.deliver(packets, now);
+    }
+}
+
+/// The state of the link to one peer.
+#[derive(Default)]
+struct Peer {
+    /// Sequence number of the next frame sent to the peer.
+    next_seq: u16,
+    /// Frames sent and yet to be acknowledged, oldest first.
+    unacked: VecDeque<Unacked>,
+    /// Packets waiting for a space in the window.
+    queue: VecDeque<Vec<u8>>,
+    receiver: Option<Receiver>,
+    stats: PeerStats,
+}
+
+impl Peer {
+    /// A data frame carrying `packet`, with an acknowledgement of the frames
+    /// received from the peer piggybacked on it when there is room.
+    fn data_frame(&mut self, seq: u16, src: u16, packet: &[u8]) -> Frame {
+        let mut frame = Frame {
+            frame_type: FrameType::Data,
+            seq,
+            flags: FLAG_ARQ,
+            src,
+            payload: packet.to_vec(),
+            quality: None,
+        };
+
+        if let Some(receiver) = self.receiver.as_mut() {
+            if packet.len() + ACK_LEN <= MAX_FRAME_SZ {
+                frame.payload.extend(receiver.ack());
+                frame.flags |= FLAG_ACK;
+            }
+        }
+
+        frame
+    }
+
+    /// Send queued packets, from station `src`, while there is space in the
+    /// window.
+    fn fill_window(&mut self, params: &ArqParams, src: u16, now: Instant, frames: &mut Vec<Frame>) {
+        while self.unacked.len() < params.window as usize {
+            let Some(packet) = self.queue.pop_front() else {
+                break;
+            };
+            let seq = self.next_seq;
+
+            self.next_seq = self.next_seq.wrapping_add(1);
+            let frame = self.data_frame(seq, src, &packet);
+
+            self.stats.sent += 1;
+            frames.push(frame.clone());
+            self.unacked.push_back(Unacked {
+                seq,
+                frame,
+                retries: 0,
+                deadline: now + params.timeout(),
+                done: false,
+            });
+        }
+    }
+
+    /// Slide the window past the frames that are done with.
+    fn slide(&mut self) {
+        while self.unacked.front().is_some_and(|u| u.done) {
+            self.unacked.pop_front();
+        }
+    }
+
+    fn process_ack(&mut self, ack: &[u8]) {
+        let expected = u16::from_be_bytes([ack[0], ack[1]]);
+        let bitmap = u32::from_be_bytes([ack[2], ack[3], ack[4], ack[5]]);
+
+        for u in self.unacked.iter_mut().filter(|u| !u.done) {
+            let offset = u.seq.wrapping_sub(expected);
+            let selected = (1..=MAX_WINDOW).contains(&offset) && bitmap & 1 << (offset - 1) != 0;
+
+            if seq_before(u.seq, expected) || selected {
+                u.done = true;
+                self.stats.acked += 1;
+            }
+        }
+
+        self.slide();
+    }
+
+    /// Retransmit the frames whose acknowledgements are overdue, giving up
+    /// on those that have run out of retries.
+    fn retransmit(&mut self, mac: &Mac, params: &ArqParams, now: Instant, frames: &mut Vec<Frame>) {
+        for u in self.unacked.iter_mut() {
+            if u.done || u.deadline > now {
+                continue;
+            }
+
+            if u.retries == params.retries {
+                u.done = true;
+                self.stats.failed += 1;
+                eprintln!(
+                    "Giving up on frame {} to {} after {} retries ({} failed so far)",
+                    u.seq,
+                    format_mac(mac),
+                    u.retries,
+                    self.stats.failed
+                );
+                continue;
+            }
+
+            u.retries += 1;
+            u.deadline = now + params.timeout();
+            self.stats.retransmitted += 1;
+            frames.push(u.frame.clone());
+        }
+
+        self.slide();
+    }
+
+    fn next_deadline(&self, params: &ArqParams) -> Option<Instant> {
+        let retransmit = self
+            .unacked
+            .iter()
+            .filter(|u| !u.done)
+            .map(|u| u.deadline)
+            .min();
+        let skip = self
+            .receiver
+            .as_ref()
+            .and_then(|r| r.gap_since)
+            .map(|t| t + params.hold());
+
+        retransmit.into_iter().chain(skip).min()
+    }
+}
+
+/// Frames to send and packets to deliver, produced by each event.
+#[derive(Debug, Default)]
+struct Output {
+    frames: Vec<Frame>,
+    packets: Vec<Vec<u8>>,
+}
+
+/// Selective repeat ARQ between the network interface and the frame encoder
+/// and decoder.  Packets from the interface are posted to `in` and frames from
+/// the decoder to `frame_in`, and frames for the encoder come out of
+/// `frame_out` and packets for the interface out of `out`.
+///
+/// Packets are Ethernet frames, and each peer is known by its address.
+/// Packets to a single peer are numbered and kept until they are
+/// acknowledged, being retransmitted after the timeout up to the retry limit.
+/// Only a window's worth of frames is sent ahead of the oldest frame yet to be
+/// acknowledged, the rest waiting in a queue.  The receiver delivers the
+/// frames from each peer in order, skipping over any the sender must have
+/// given up on, and acknowledges them with the next sequence number it
+/// expects and a bitmap of the frames it has received after that.  The
+/// acknowledgement is piggybacked on a data frame going back to the peer if
+/// there is one, otherwise it is sent in a control frame of its own.
+///
+/// Packets to broadcast and multicast addresses are sent without ARQ, as are
+/// all packets when it is disabled.  Frames between other stations are only
+/// overheard: they are passed to the interface without being acknowledged,
+/// and the retransmissions of those sent reliably are dropped.  Any message
+/// on `stats` responds with the statistics of each peer, as a string.
+pub struct Arq {
+    params: ArqParams,
+    /// Sequence number of the next frame sent without ARQ.
+    seq: u16,
+    /// Our address, that of the TAP interface.
+    local: Mac,
+    /// `station_id` of `local`.
+    station: u16,
+    peers: BTreeMap<Mac, Peer>,
+    /// The source, destination and sequence number of the reliable frames
+    /// between other stations overheard most recently, oldest first.
+    overheard: VecDeque<(Mac, Mac, u16)>,
+}
+
+impl Arq {
+    /// `local` is the address of the TAP interface.
+    pub fn new(params: ArqParams, local: Mac) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("Arq").build(),
+            StreamIoBuilder::new().build(),
+            MessageIoBuilder::new()
+                .add_input("in", Self::packet_handler)
+                .add_input("frame_in", Self::frame_handler)
+                .add_input("stats", Self::stats_handler)
+                .add_output("out")
+                .add_output("frame_out")
+                .build(),
+            Self::create(params, local),
+        )
+    }
+
+    fn create(params: ArqParams, local: Mac) -> Self {
+        assert!(params.window > 0 && params.window <= MAX_WINDOW);
+
+        Arq {
+            params,
+            seq: 0,
+            local,
+            station: station_id(&local),
+            peers: BTreeMap::new(),
+            overheard: VecDeque::new(),
+        }
+    }
+
+    fn addrs(packet: &[u8]) -> (Mac, Mac) {
+        (
+            packet[..6].try_into().unwrap(),
+            packet[6..ADDRS_LEN].try_into().unwrap(),
+        )
+    }
+
+    fn send_packet(&mut self, packet: Vec<u8>, now: Instant, out: &mut Output) {
+        let reliable = self.params.enabled && packet.len() >= ADDRS_LEN && packet[0] & 1 == 0;
+
+        if !reliable {
+            out.frames.push(Frame {
+                frame_type: FrameType::Data,
+                seq: self.seq,
+                flags: 0,
+                src: self.station,
+                payload: packet,
+                quality: None,
+            });
+            self.seq = self.seq.wrapping_add(1);
+            return;
+        }
+
+        let (dst, _) = Self::addrs(&packet);
+        let peer = self.peers.entry(dst).or_default();
+
+        if peer.queue.len() == QUEUE_LEN {
+            peer.stats.overflowed += 1;
+            return;
+        }
+
+        peer.queue.push_back(packet);
+        peer.fill_window(&self.params, self.station, now, &mut out.frames);
+    }
+
+    fn receive_frame(&mut self, mut frame: Frame, now: Instant, out: &mut Output) {
+        let ack = if frame.flags & FLAG_ACK != 0 {
+            if frame.payload.len() < ADDRS_LEN + ACK_LEN {
+                return;
+            }
+
+            Some(frame.payload.split_off(frame.payload.len() - ACK_LEN))
+        } else {
+            None
+        };
+
+        let reliable = self.params.enabled
+            && frame.frame_type == FrameType::Data
+            && frame.flags & FLAG_ARQ != 0;
+
+        if frame.payload.len() < ADDRS_LEN {
+            if frame.frame_type == FrameType::Data {
+                out.packets.push(frame.payload);
+            }
+            return;
+        }
+
+        let (dst, src) = Self::addrs(&frame.payload);
+
+        // Our own frames, heard on a channel shared with the other stations.
+        if src == self.local {
+            return;
+        }
+
+        // Frames to someone else are only overheard.
+        if dst != self.local {
+            if frame.frame_type == FrameType::Data && self.overhear(src, dst, &frame) {
+                out.packets.push(frame.payload);
+            }
+            return;
+        }
+
+        if let Some(ack) = ack {
+            if let Some(peer) = self.peers.get_mut(&src) {
+                peer.process_ack(&ack);
+            }
+        }
+
+        match frame.frame_type {
+            FrameType::Data if reliable => {
+                self.receive_reliable(src, frame.seq, frame.payload, now, out)
+            }
+            FrameType::Data => out.packets.push(frame.payload),
+            FrameType::Control => (),
+        }
+
+        let Some(peer) = self.peers.get_mut(&src) else {
+            return;
+        };
+
+        // Any frames sent now carry the acknowledgement of this one.
+        peer.fill_window(&self.params, self.station, now, &mut out.frames);
+
+        if let Some(receiver) = peer.receiver.as_mut().filter(|r| r.ack_due) {
+            let mut payload = src.to_vec();
+
+            payload.extend(self.local);
+            payload.extend(receiver.ack());
+            out.frames.push(Frame {
+                frame_type: FrameType::Control,
+                seq: 0,
+                flags: FLAG_ACK,
+                src: self.station,
+                payload,
+                quality: None,
+            });
+        }
+    }
+
+    /// Whether a data frame from `src` to `dst`, another station, is heard for
+    /// the first time.  Only frames sent reliably can be heard again.
+    fn overhear(&mut self, src: Mac, dst: Mac, frame: &Frame) -> bool {
+        if frame.flags & FLAG_ARQ == 0 {
+            return true;
+        }
+
+        let key = (src, dst, frame.seq);
+
+        if self.overheard.contains(&key) {
+            return false;
+        }
+
+        if self.overheard.len() == OVERHEARD_LEN {
+            self.overheard.pop_front();
+        }
+        self.overheard.push_back(key);
+
+        true
+    }
+
+    /// Deliver a frame sent reliably from `src`, in order, and arrange for it
+    /// to be acknowledged.
+    fn receive_reliable(
+        &mut self,
+        src: Mac,
+        seq: u16,
+        packet: Vec<u8>,
+        now: Instant,
+        out: &mut Output,
+    ) {
+        let window = self.params.window;
+        let peer = self.peers.entry(src).or_default();
+        let receiver = peer.receiver.get_or_insert_with(|| Receiver {
+            expected: 0,
+            buffer: HashMap::new(),
+            gap_since: None,
+            ack_due: false,
+        });
+        let ahead = seq.wrapping_sub(receiver.expected);
+        let behind = receiver.expected.wrapping_sub(seq);
+        let delivered = out.packets.len();
+
+        if ahead >= window && behind > window {
+            // The sender has given up on the frames we're waiting for, or
+            // started afresh.
+            receiver.skip_gap(&mut out.packets, now);
+            receiver.expected = seq;
+        }
+
+        if seq_before(seq, receiver.expected) || receiver.buffer.contains_key(&seq) {
+            peer.stats.duplicates += 1;
+        } else {
+            receiver.buffer.insert(seq, packet);
+            receiver.deliver(&mut out.packets, now);
+        }
+
+        peer.stats.received += (out.packets.len() - delivered) as u64;
+        receiver.ack_due = true;
+    }
+
+    /// Handle the timeouts that have expired by `now`.
+    fn poll(&mut self, now: Instant, out: &mut Output) {
+        for (mac, peer) in self.peers.iter_mut() {
+            peer.retransmit(mac, &self.params, now, &mut out.frames);
+            peer.fill_window(&self.params, self.station, now, &mut out.frames);
+
+            if let Some(receiver) = peer.receiver.as_mut() {
+                if receiver
+                    .gap_since
+                    .is_some_and(|t| t + self.params.hold() <= now)
+                {
+                    let delivered = out.packets.len();
+
+                    receiver.skip_gap(&mut out.packets, now);
+                    peer.stats.received += (out.packets.len() - delivered) as u64;
+                }
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .filter_map(|p| p.next_deadline(&self.params))
+            .min()
+    }
+
+    fn stats(&self) -> String {
+        self.peers
+            .iter()
+            .map(|(mac, peer)| format!("{} {}\n", format_mac(mac), peer.stats))
+            .collect()
+    }
+
+    async fn post(mio: &mut MessageIo<Self>, out: Output) {
+        for frame in out.frames {
+            mio.post(1, frame.to_pmt()).await;
+        }
+
+        for packet in out.packets {
+            mio.post(0, Pmt::Blob(packet)).await;
+        }
+    }
+
+    #[message_handler]
+    async fn packet_handler(
+        &mut self,
+        mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Pmt::Blob(packet) = p {
+            let mut out = Output::default();
+
+            self.send_packet(packet, Instant::now(), &mut out);
+            Self::post(mio, out).await;
+        }
+
+        Ok(Pmt::Null)
+    }
+
+    #[message_handler]
+    async fn frame_handler(
+        &mut self,
+        mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Some(frame) = Frame::from_pmt(&p) {
+            let mut out = Output::default();
+
+            self.receive_frame(frame, Instant::now(), &mut out);
+            Self::post(mio, out).await;
+        }
+
+        Ok(Pmt::Null)
+    }
+
+    #[message_handler]
+    async fn stats_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        Ok(Pmt::String(self.stats()))
+    }
+}
+
+#[async_trait]
+impl Kernel for Arq {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        _sio: &mut StreamIo,
+        mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let mut out = Output::default();
+
+        self.poll(Instant::now(), &mut out);
+        Self::post(mio, out).await;
+
+        // Called again once the next timeout expires, or sooner when a
+        // message arrives.
+        if let Some(deadline) = self.next_deadline() {
+            io.block_on(async move {
+                async_io::Timer::at(deadline).await;
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::header::{Frame, FrameType};
+
+    use super::{station_id, Arq, ArqParams, Output, FLAG_ACK, FLAG_ARQ};
+
+    const A: [u8; 6] = [2, 0, 0, 0, 0, 0xa];
+    const B: [u8; 6] = [2, 0, 0, 0, 0, 0xb];
+    const C: [u8; 6] = [2, 0, 0, 0, 0, 0xc];
+
+    const PARAMS: ArqParams = ArqParams {
+        enabled: true,
+        window: 8,
+        retries: 5,
+        timeout_ms: 500,
+    };
+
+    fn packet(dst: [u8; 6], src: [u8; 6], n: u8) -> Vec<u8> {
+        [&dst[..], &src[..], &[0x08, 0x00, n]].concat()
+    }
+
+    fn send(arq: &mut Arq, packets: &[Vec<u8>], now: Instant) -> Output {
+        let mut out = Output::default();
+
+        for p in packets {
+            arq.send_packet(p.clone(), now, &mut out);
+        }
+
+        out
+    }
+
+    fn receive(arq: &mut Arq, frames: &[Frame], now: Instant) -> Output {
+        let mut out = Output::default();
+
+        for f in frames {
+            arq.receive_frame(f.clone(), now, &mut out);
+        }
+
+        out
+    }
+
+    fn packets(n: std::ops::Range<u8>) -> Vec<Vec<u8>> {
+        n.map(|i| packet(B, A, i)).collect()
+    }
+
+    #[test]
+    fn acknowledged() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+        let mut b = Arq::create(PARAMS, B);
+
+        let sent = send(&mut a, &packets(0..3), now);
+        assert_eq!(sent.frames.len(), 3);
+        assert!(sent
+            .frames
+            .iter()
+            .enumerate()
+            .all(|(i, f)| f.seq == i as u16 && f.flags == FLAG_ARQ && f.src == station_id(&A)));
+
+        let received = receive(&mut b, &sent.frames, now);
+        assert_eq!(received.packets, packets(0..3));
+        assert_eq!(received.frames.len(), 3);
+        assert!(received
+            .frames
+            .iter()
+            .all(|f| f.frame_type == FrameType::Control && f.flags == FLAG_ACK));
+
+        receive(&mut a, &received.frames, now);
+        assert!(a.next_deadline().is_none());
+        assert_eq!(a.peers[&B].stats.acked, 3);
+        assert_eq!(b.peers[&A].stats.received, 3);
+    }
+
+    #[test]
+    fn selective_repeat() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+        let mut b = Arq::create(PARAMS, B);
+
+        let mut sent = send(&mut a, &packets(0..4), now).frames;
+        let lost = sent.remove(1);
+
+        let received = receive(&mut b, &sent, now);
+        assert_eq!(received.packets, packets(0..1));
+        receive(&mut a, &received.frames, now);
+        assert_eq!(a.peers[&B].stats.acked, 3);
+
+        // Only the lost frame is retransmitted, unchanged, once the timeout
+        // expires.
+        let mut out = Output::default();
+        a.poll(now + Duration::from_millis(499), &mut out);
+        assert!(out.frames.is_empty());
+        a.poll(now + Duration::from_millis(500), &mut out);
+        assert_eq!(out.frames, vec![lost]);
+
+        let received = receive(&mut b, &out.frames, now);
+        assert_eq!(received.packets, packets(1..4));
+        receive(&mut a, &received.frames, now);
+        assert!(a.next_deadline().is_none());
+        assert_eq!(a.peers[&B].stats.retransmitted, 1);
+    }
+
+    #[test]
+    fn give_up() {
+        let mut now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+
+        send(&mut a, &packets(0..1), now);
+
+        for _ in 0..PARAMS.retries {
+            let mut out = Output::default();
+
+            now = a.next_deadline().unwrap();
+            a.poll(now, &mut out);
+            assert_eq!(out.frames.len(), 1);
+        }
+
+        let mut out = Output::default();
+        a.poll(a.next_deadline().unwrap(), &mut out);
+        assert!(out.frames.is_empty());
+        assert!(a.next_deadline().is_none());
+        assert_eq!(a.peers[&B].stats.retransmitted, PARAMS.retries as u64);
+        assert_eq!(a.peers[&B].stats.failed, 1);
+    }
+
+    #[test]
+    fn duplicate() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+        let mut b = Arq::create(PARAMS, B);
+
+        let sent = send(&mut a, &packets(0..1), now).frames;
+        let received = receive(&mut b, &[sent[0].clone(), sent[0].clone()], now);
+
+        // A lost acknowledgement has the frame sent again, which must be
+        // acknowledged but not delivered again.
+        assert_eq!(received.packets, packets(0..1));
+        assert_eq!(received.frames.len(), 2);
+        assert_eq!(b.peers[&A].stats.duplicates, 1);
+    }
+
+    #[test]
+    fn window() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+        let mut b = Arq::create(PARAMS, B);
+
+        let sent = send(&mut a, &packets(0..10), now).frames;
+        assert_eq!(sent.len(), 8);
+
+        let acks = receive(&mut b, &sent[..1], now).frames;
+        let sent = receive(&mut a, &acks, now).frames;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].seq, 8);
+    }
+
+    #[test]
+    fn piggyback() {
+        let now = Instant::now();
+        let params = ArqParams {
+            window: 1,
+            ..PARAMS
+        };
+        let mut a = Arq::create(params, A);
+        let mut b = Arq::create(params, B);
+        let replies: Vec<_> = (0..2).map(|i| packet(A, B, i)).collect();
+
+        let a_sent = send(&mut a, &packets(0..2), now).frames;
+        let b_sent = send(&mut b, &replies, now).frames;
+        let b_acks = receive(&mut b, &a_sent, now).frames;
+        let a_acks = receive(&mut a, &b_sent, now).frames;
+
+        // Both windows are full, so the acknowledgements go on their own.
+        assert_eq!(b_acks.len(), 1);
+        assert_eq!(a_acks.len(), 1);
+        assert_eq!(b_acks[0].frame_type, FrameType::Control);
+
+        // The acknowledgement of B's first frame lets it send its second,
+        // which carries the acknowledgement of A's first frame.
+        let b_sent = receive(&mut b, &a_acks, now).frames;
+        assert_eq!(b_sent.len(), 1);
+        assert_eq!(b_sent[0].flags, FLAG_ARQ | FLAG_ACK);
+
+        // That in turn lets A send its second frame, which acknowledges B's
+        // second frame without a separate acknowledgement.
+        let received = receive(&mut a, &b_sent, now);
+        assert_eq!(received.packets, replies[1..]);
+        assert_eq!(received.frames.len(), 1);
+        assert_eq!(received.frames[0].seq, 1);
+        assert_eq!(received.frames[0].flags, FLAG_ARQ | FLAG_ACK);
+        assert_eq!(received.frames[0].payload[..15], packets(1..2)[0]);
+
+        let received = receive(&mut b, &received.frames, now);
+        assert_eq!(received.packets, packets(1..2));
+        assert_eq!(b.peers[&A].stats.acked, 2);
+    }
+
+    #[test]
+    fn skip_gap() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+        let mut b = Arq::create(PARAMS, B);
+
+        let sent = send(&mut a, &packets(0..3), now).frames;
+        let received = receive(&mut b, &[sent[0].clone(), sent[2].clone()], now);
+        assert_eq!(received.packets, packets(0..1));
+
+        // Once the sender must have given up on the missing frame, the ones
+        // after it are delivered.
+        let deadline = b.next_deadline().unwrap();
+        assert_eq!(deadline, now + Duration::from_millis(3000));
+
+        let mut out = Output::default();
+        b.poll(deadline, &mut out);
+        assert_eq!(out.packets, packets(2..3));
+        assert!(b.next_deadline().is_none());
+    }
+
+    #[test]
+    fn unreliable() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+        let mut b = Arq::create(ArqParams::default(), B);
+        let broadcast = packet([0xff; 6], A, 0);
+
+        let sent = send(&mut a, &[broadcast], now).frames;
+        assert_eq!(sent[0].flags, 0);
+        assert!(a.next_deadline().is_none());
+
+        let replies: Vec<_> = (0..2).map(|i| packet(A, B, i)).collect();
+        let sent = send(&mut b, &replies, now).frames;
+        assert_eq!(
+            sent.iter().map(|f| (f.seq, f.flags)).collect::<Vec<_>>(),
+            vec![(0, 0), (1, 0)]
+        );
+
+        let received = receive(&mut a, &sent, now);
+        assert_eq!(received.packets, replies);
+        assert!(received.frames.is_empty());
+    }
+
+    #[test]
+    fn own_frames() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+
+        // On a shared channel a station hears its own frames.
+        let sent = send(
+            &mut a,
+            &[packets(0..1)[0].clone(), packet([0xff; 6], A, 1)],
+            now,
+        );
+        let received = receive(&mut a, &sent.frames, now);
+
+        assert!(received.packets.is_empty());
+        assert!(received.frames.is_empty());
+    }
+
+    #[test]
+    fn overheard() {
+        let now = Instant::now();
+        let mut a = Arq::create(PARAMS, A);
+        let mut b = Arq::create(PARAMS, B);
+        let mut c = Arq::create(PARAMS, C);
+
+        let sent = send(&mut a, &packets(0..2), now).frames;
+        let acks = receive(&mut b, &sent, now).frames;
+
+        // C, which hasn't sent anything yet, passes the frames between A and
+        // B on once, retransmissions and all.  It doesn't acknowledge them on
+        // B's behalf, or take B's acknowledgements as its own.
+        let received = receive(&mut c, &[&sent[..], &sent[..], &acks[..]].concat(), now);
+        assert_eq!(received.packets, packets(0..2));
+        assert!(received.frames.is_empty());
+        assert!(c.peers.is_empty());
+    }
+}
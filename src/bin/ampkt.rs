@@ -1,16 +1,24 @@
 use anyhow::{Context, Result};
 use clap::Parser;
 use futuresdr::{
-    blocks::{SoapySinkBuilder, SoapySourceBuilder},
+    blocks::{NullSink, SoapySinkBuilder, SoapySourceBuilder},
     macros::connect,
     runtime::{Flowgraph, Runtime},
 };
 
 use ampkt::{
+    agc::{Agc, AgcParams},
+    arq::{Arq, ArqParams},
     carrier_sync::CarrierSync,
     clock_sync::ClockSync,
-    frame::{FrameDecoder, FrameEncoder},
+    csma::{CarrierSense, Csma, CsmaParams},
+    equaliser::{Equaliser, EqualiserParams},
+    frame::{Coding, FrameDecoder, FrameEncoder},
+    freq_sync::FreqSync,
+    pulse_shape::PulseShape,
     qam::{QamDemod, QamMod},
+    sym::Symbol,
+    sym_sync::{DEFAULT_THRESHOLD, MAX_THRESHOLD},
     tap::Tap,
 };
 
@@ -20,6 +28,29 @@ struct Args {
     tx_gain: f64,
     #[clap(short,long)]
     rx_gain: f64,
+    #[command(flatten)]
+    coding: Coding,
+    #[command(flatten)]
+    pulse: PulseShape,
+    #[command(flatten)]
+    agc: AgcParams,
+    #[command(flatten)]
+    equaliser: EqualiserParams,
+    #[command(flatten)]
+    arq: ArqParams,
+    #[command(flatten)]
+    csma: CsmaParams,
+    /// Largest number of bit errors allowed in the 32 bit sync word.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_THRESHOLD,
+        value_parser = clap::value_parser!(u32).range(..=MAX_THRESHOLD as i64)
+    )]
+    sync_threshold: u32,
+    /// Pass the symbols straight through the carrier sync, for use with
+    /// `--modulation dqpsk` when the oscillators are too unstable to track.
+    #[arg(long)]
+    no_carrier_sync: bool,
     soapy_device: String,
     tx_freq: f64,
     rx_freq: f64,
@@ -32,12 +63,16 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let tap = Tap::new("tap%d", tun_tap::Mode::Tap)?;
+    let (tap, mac) = Tap::new("tap%d", tun_tap::Mode::Tap)?;
+
+    let arq = Arq::new(args.arq, mac);
+
+    let csma = Csma::new(args.csma);
 
     // TX Blocks.
-    let frame_encoder = FrameEncoder::new();
+    let frame_encoder = FrameEncoder::new(args.coding);
 
-    let qam_mod = QamMod::new(10);
+    let qam_mod = QamMod::new(10, args.pulse);
 
     let tx_dev =
         soapysdr::Device::new(args.soapy_device.as_str()).context("Could not find SDR device")?;
@@ -59,19 +94,44 @@ fn main() -> Result<()> {
         .gain(args.rx_gain)
         .build();
 
-    let clock_sync = ClockSync::new(10, 20.0);
+    let carrier_sense = CarrierSense::new(args.csma.threshold);
+
+    let freq_sync = FreqSync::new(SAMP_RATE as f32, args.coding.modulation);
+
+    let matched_filter = args.pulse.matched_filter(10);
+
+    let agc = Agc::new(args.agc);
+
+    let clock_sync = ClockSync::new(10.0, 0.01, 0.707);
+
+    let equaliser = Equaliser::new(args.equaliser, args.coding.modulation);
+
+    let carrier_bw = if args.no_carrier_sync { 0.0 } else { 0.02 };
+    let carrier_sync = CarrierSync::new(carrier_bw, 0.707, args.coding.modulation);
 
-    let carrier_sync = CarrierSync::new();
+    let qam_demod = QamDemod::new(args.coding, args.sync_threshold);
 
-    let qam_demod = QamDemod::new();
+    // Only the soft decisions are used by the frame decoder.
+    let hard_sink = NullSink::<Symbol>::new();
 
-    let frame_decoder = FrameDecoder::new();
+    let frame_decoder = FrameDecoder::new(args.coding);
 
     connect!(fg,
              // TX Path
-             tap | frame_encoder > qam_mod > tx_soapy_dev;
+             tap | arq;
+             arq.frame_out | csma;
+             csma | frame_encoder.frame;
+             frame_encoder > qam_mod > tx_soapy_dev;
              // RX Path
-             rx_soapy_dev > clock_sync > carrier_sync > qam_demod > frame_decoder | tap);
+             rx_soapy_dev > carrier_sense > freq_sync > matched_filter > agc > clock_sync > equaliser > carrier_sync > qam_demod > hard_sink;
+             qam_demod.soft > frame_decoder;
+             frame_decoder.data | qam_demod.data;
+             frame_decoder.frame | arq.frame_in;
+             arq | tap;
+             // Channel access
+             carrier_sense.busy | csma.carrier;
+             frame_decoder.busy | csma.decoding;
+             frame_encoder.busy | csma.sending);
 
     Runtime::new().run(fg)?;
 
@@ -3,19 +3,51 @@ use std::path::PathBuf;
 use anyhow::{Context, Result};
 use clap::Parser;
 use futuresdr::{
-    blocks::{FileSink, FileSource, MessagePipe, SoapySourceBuilder},
+    blocks::{FileSink, FileSource, MessagePipe, NullSink, SoapySourceBuilder},
     futures::{channel::mpsc, executor::block_on, StreamExt},
     macros::connect,
     num_complex::Complex32,
     runtime::{Flowgraph, Pmt, Runtime},
 };
 
-use ampkt::{carrier_sync::CarrierSync, clock_sync::ClockSync, frame::FrameDecoder, qam::QamDemod};
+use ampkt::{
+    agc::{Agc, AgcParams},
+    carrier_sync::CarrierSync,
+    clock_sync::ClockSync,
+    equaliser::{Equaliser, EqualiserParams},
+    frame::{Coding, FrameDecoder},
+    freq_sync::FreqSync,
+    header::Frame,
+    link_quality::{LinkQuality, Quality},
+    pulse_shape::PulseShape,
+    qam::QamDemod,
+    sym::Symbol,
+    sym_sync::{DEFAULT_THRESHOLD, MAX_THRESHOLD},
+};
 
 #[derive(Parser)]
 struct Args {
     #[command(subcommand)]
     cmd: RxType,
+    #[command(flatten)]
+    coding: Coding,
+    #[command(flatten)]
+    pulse: PulseShape,
+    #[command(flatten)]
+    agc: AgcParams,
+    #[command(flatten)]
+    equaliser: EqualiserParams,
+    /// Largest number of bit errors allowed in the 32 bit sync word.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_THRESHOLD,
+        value_parser = clap::value_parser!(u32).range(..=MAX_THRESHOLD as i64)
+    )]
+    sync_threshold: u32,
+    /// Pass the symbols straight through the carrier sync, for use with
+    /// `--modulation dqpsk` when the oscillators are too unstable to track.
+    #[arg(long)]
+    no_carrier_sync: bool,
 }
 
 #[derive(clap::Subcommand)]
@@ -54,13 +86,27 @@ fn main() -> Result<()> {
         }
     };
 
-    let clock_sync = ClockSync::new(10, 20.0);
+    let freq_sync = FreqSync::new(800_000.0, args.coding.modulation);
+
+    let matched_filter = args.pulse.matched_filter(10);
+
+    let agc = Agc::new(args.agc);
+
+    let clock_sync = ClockSync::new(10.0, 0.01, 0.707);
+
+    let equaliser = Equaliser::new(args.equaliser, args.coding.modulation);
+
+    let carrier_bw = if args.no_carrier_sync { 0.0 } else { 0.02 };
+    let carrier_sync = CarrierSync::new(carrier_bw, 0.707, args.coding.modulation);
+
+    let link_quality = LinkQuality::new(args.coding.modulation);
 
-    let carrier_sync = CarrierSync::new();
+    let qam_demod = QamDemod::new(args.coding, args.sync_threshold);
 
-    let qam_demod = QamDemod::new();
+    // Only the soft decisions are used by the frame decoder.
+    let hard_sink = NullSink::<Symbol>::new();
 
-    let frame_decoder = FrameDecoder::new();
+    let frame_decoder = FrameDecoder::new(args.coding);
 
     let (tx, mut rx) = mpsc::channel::<Pmt>(100);
 
@@ -70,7 +116,13 @@ fn main() -> Result<()> {
     let clock_sync_sink = FileSink::<Complex32>::new("clock_sync.cf32");
     let carrier_sync_sink = FileSink::<Complex32>::new("carrier_sync.cf32");
 
-    connect!(fg, src > clock_sync > carrier_sync > qam_demod > frame_decoder | message_sink;
+    connect!(fg, src > freq_sync > matched_filter > agc > clock_sync > equaliser > carrier_sync > qam_demod > hard_sink;
+             qam_demod.soft > frame_decoder;
+             frame_decoder.frame | message_sink;
+             frame_decoder.data | qam_demod.data;
+             carrier_sync > link_quality;
+             link_quality.quality | message_sink;
+             freq_sync.freq | message_sink;
              src > raw_signal_sink;
              carrier_sync > carrier_sync_sink;
              clock_sync > clock_sync_sink);
@@ -81,7 +133,21 @@ fn main() -> Result<()> {
     rt.block_on(async move {
         while let Some(x) = rx.next().await {
             match x {
-                Pmt::Blob(frame) => println!("RX'd frame: {frame:X?}"),
+                Pmt::Any(_) => match Frame::from_pmt(&x) {
+                    Some(frame) => {
+                        println!("RX'd frame: {:X?}", frame.payload);
+
+                        if let Some(q) = frame.quality {
+                            println!("Frame quality: {q}");
+                        }
+                    }
+                    None => eprintln!("Malformed frame message"),
+                },
+                Pmt::VecF32(_) => match Quality::from_pmt(&x) {
+                    Some(q) => println!("Link quality: {q}"),
+                    None => eprintln!("Malformed link quality message"),
+                },
+                Pmt::F32(offset) => println!("Frequency offset: {offset:.0} Hz"),
                 Pmt::Null => break,
                 _ => eprintln!("Unexpected message type from qam demot"),
             }
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use clap::Parser;
+use futuresdr::{
+    blocks::{MessagePipe, NullSink},
+    futures::{channel::mpsc, executor::block_on, stream, StreamExt},
+    macros::connect,
+    runtime::{Flowgraph, Pmt, Runtime},
+};
+
+use ampkt::{
+    agc::{Agc, AgcParams},
+    carrier_sync::CarrierSync,
+    channel::{Channel, ChannelParams},
+    clock_sync::ClockSync,
+    equaliser::{Equaliser, EqualiserParams},
+    frame::{Coding, FrameDecoder, FrameEncoder, MAX_FRAME_SZ},
+    freq_sync::FreqSync,
+    header::Frame,
+    pulse_shape::PulseShape,
+    qam::{QamDemod, QamMod},
+    sym::Symbol,
+    sym_sync::{DEFAULT_THRESHOLD, MAX_THRESHOLD},
+};
+
+/// Sends test frames through the channel simulator and the receive chain, so
+/// that the modem can be tried out without any hardware.
+#[derive(Parser)]
+struct Args {
+    /// Length of the test frames, in bytes.
+    #[arg(
+        long,
+        default_value_t = 64,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=MAX_FRAME_SZ as u64)
+    )]
+    size: usize,
+    #[command(flatten)]
+    coding: Coding,
+    #[command(flatten)]
+    pulse: PulseShape,
+    #[command(flatten)]
+    agc: AgcParams,
+    #[command(flatten)]
+    equaliser: EqualiserParams,
+    #[command(flatten)]
+    channel: ChannelParams,
+    /// Largest number of bit errors allowed in the 32 bit sync word.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_THRESHOLD,
+        value_parser = clap::value_parser!(u32).range(..=MAX_THRESHOLD as i64)
+    )]
+    sync_threshold: u32,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut fg = Flowgraph::new();
+
+    let frame_encoder = FrameEncoder::new(args.coding);
+
+    let qam_mod = QamMod::new(10, args.pulse);
+
+    let channel = Channel::new(args.channel, 800_000.0);
+
+    let freq_sync = FreqSync::new(800_000.0, args.coding.modulation);
+
+    let matched_filter = args.pulse.matched_filter(10);
+
+    let agc = Agc::new(args.agc);
+
+    let clock_sync = ClockSync::new(10.0, 0.01, 0.707);
+
+    let equaliser = Equaliser::new(args.equaliser, args.coding.modulation);
+
+    let carrier_sync = CarrierSync::new(0.02, 0.707, args.coding.modulation);
+
+    let qam_demod = QamDemod::new(args.coding, args.sync_threshold);
+
+    // Only the soft decisions are used by the frame decoder.
+    let hard_sink = NullSink::<Symbol>::new();
+
+    let frame_decoder = FrameDecoder::new(args.coding);
+
+    let (tx, rx) = mpsc::channel::<Pmt>(100);
+
+    let message_sink = MessagePipe::new(tx);
+
+    // The next test frame is sent as soon as the encoder has finished sending
+    // the last one, so that the frames go through the simulator back to back
+    // however fast it runs.
+    let (busy_tx, busy_rx) = mpsc::channel::<Pmt>(100);
+
+    let busy_sink = MessagePipe::new(busy_tx);
+
+    connect!(fg, frame_encoder > qam_mod > channel > freq_sync > matched_filter > agc > clock_sync > equaliser > carrier_sync > qam_demod > hard_sink;
+             qam_demod.soft > frame_decoder;
+             frame_decoder.frame | message_sink;
+             frame_decoder.data | qam_demod.data;
+             frame_encoder.busy | busy_sink);
+
+    let rt = Runtime::new();
+    let (_fg, mut handle) = block_on(rt.start(fg));
+
+    let test_frame = Pmt::Blob((0..args.size).map(|i| i as u8).collect());
+
+    rt.block_on(async move {
+        let mut messages = stream::select(rx.map(|x| (false, x)), busy_rx.map(|x| (true, x)));
+
+        handle.call(frame_encoder, 0, test_frame.clone()).await?;
+
+        while let Some((busy, x)) = messages.next().await {
+            match x {
+                Pmt::U32(0) if busy => handle.call(frame_encoder, 0, test_frame.clone()).await?,
+                Pmt::U32(_) if busy => (),
+                Pmt::Any(_) => match Frame::from_pmt(&x) {
+                    Some(frame) => {
+                        println!("RX'd frame: {:X?}", frame.payload);
+
+                        if let Some(q) = frame.quality {
+                            println!("Frame quality: {q}");
+                        }
+                    }
+                    None => eprintln!("Malformed frame message"),
+                },
+                Pmt::Null => break,
+                _ => eprintln!("Unexpected message type from frame decoder"),
+            }
+        }
+
+        anyhow::Ok(())
+    })?;
+
+    Ok(())
+}
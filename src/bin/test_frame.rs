@@ -8,12 +8,20 @@ use futuresdr::{
     runtime::{Flowgraph, Runtime},
 };
 
-use ampkt::{frame::FrameEncoder, qam::QamMod};
+use ampkt::{
+    frame::{Coding, FrameEncoder},
+    pulse_shape::PulseShape,
+    qam::QamMod,
+};
 
 #[derive(Parser)]
 struct Args {
     soapy_device: String,
     tx_freq: f64,
+    #[command(flatten)]
+    coding: Coding,
+    #[command(flatten)]
+    pulse: PulseShape,
 }
 
 fn main() -> Result<()> {
@@ -27,9 +35,9 @@ fn main() -> Result<()> {
         None,
     );
 
-    let frame_encoder = FrameEncoder::new();
+    let frame_encoder = FrameEncoder::new(args.coding);
 
-    let qam_mod = QamMod::new(10);
+    let qam_mod = QamMod::new(10, args.pulse);
 
     let dev =
         soapysdr::Device::new(args.soapy_device.as_str()).context("Could not find SDR device")?;
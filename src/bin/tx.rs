@@ -5,16 +5,21 @@ use futuresdr::{
     runtime::{Flowgraph, Runtime},
 };
 
-use ampkt::{frame::FrameEncoder, qam::QamMod, tap::Tap};
+use ampkt::{
+    frame::{Coding, FrameEncoder},
+    pulse_shape::PulseShape,
+    qam::QamMod,
+    tap::Tap,
+};
 
 fn main() -> Result<()> {
     let mut fg = Flowgraph::new();
 
-    let tap = Tap::new("tap%d", tun_tap::Mode::Tap).unwrap();
+    let (tap, _mac) = Tap::new("tap%d", tun_tap::Mode::Tap)?;
 
-    let frame_encoder = FrameEncoder::new();
+    let frame_encoder = FrameEncoder::new(Coding::default());
 
-    let qam_mod = QamMod::new(10);
+    let qam_mod = QamMod::new(10, PulseShape::default());
 
     let dev = soapysdr::Device::new("driver=uhd,addr=192.168.50.2")
         .context("Could not find SDR device")?;
@@ -1,43 +1,193 @@
 use std::f32::consts::PI;
 
-use futuresdr::{blocks::Apply, num_complex::Complex32, runtime::Block};
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    macros::message_handler,
+    num_complex::Complex32,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
 
-pub struct CarrierSync;
+use crate::constellation::{Constellation, Modulation};
+use crate::loop_filter::LoopFilter;
+
+/// Slope of the normalised phase error (per radian) around the lock point.
+const DETECTOR_GAIN: f32 = 1.0;
+
+/// Largest frequency offset, in radians per symbol, that the loop will track
+/// with the decisions of `modulation`.  A QPSK constellation rotated by more
+/// than an eighth of a turn per symbol is indistinguishable from one rotating
+/// the other way, and with 8PSK decisions the ambiguity is at π/8 per symbol.
+fn max_freq(modulation: Modulation) -> f32 {
+    PI / modulation.symmetry() as f32
+}
+
+/// Carrier recovery using a decision directed Costas loop.  The phase error
+/// is the angle between each symbol and the nearest point of the
+/// modulation's decision constellation, and drives a second order loop whose
+/// integrator holds the estimated frequency offset, so a steady frequency
+/// difference between the SDRs is tracked with no standing phase error.
+///
+/// With 8PSK decisions the loop can just as well lock an eighth of a turn
+/// away from the QPSK points of the sync words and header, which the
+/// demodulator resolves when it finds the sync word.
+///
+/// The loop can be controlled through the message ports: any message on
+/// `reset` clears the phase and frequency estimates, an `F32` on `freq` sets
+/// the frequency estimate (in radians per symbol) and an `F32` on `bandwidth`
+/// changes the loop bandwidth.  All of them respond with the current frequency
+/// estimate.  A loop bandwidth of zero disables the loop, clearing its phase
+/// and frequency estimates so that the samples are left untouched.
+pub struct CarrierSync {
+    constellation: Box<dyn Constellation>,
+    loop_filter: LoopFilter,
+    /// Estimated phase of the incoming carrier, in radians.
+    phase: f32,
+}
 
 impl CarrierSync {
-    fn threshold(x: f32) -> f32 {
-        if x > 0.0 {
-            1.0
+    /// `loop_bw` is the loop noise bandwidth normalised to the symbol rate
+    /// and `damping` the loop damping factor.
+    pub fn new(loop_bw: f32, damping: f32, modulation: Modulation) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("CarrierSync").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .add_output("out", std::mem::size_of::<Complex32>())
+                .build(),
+            MessageIoBuilder::new()
+                .add_input("reset", Self::reset_handler)
+                .add_input("freq", Self::freq_handler)
+                .add_input("bandwidth", Self::bandwidth_handler)
+                .build(),
+            Self::create(loop_bw, damping, modulation),
+        )
+    }
+
+    fn create(loop_bw: f32, damping: f32, modulation: Modulation) -> Self {
+        CarrierSync {
+            constellation: modulation.decision_constellation(),
+            loop_filter: LoopFilter::new(loop_bw, damping, DETECTOR_GAIN, max_freq(modulation)),
+            phase: 0.0,
+        }
+    }
+
+    /// Estimated frequency offset of the incoming carrier, in radians per
+    /// symbol.
+    fn freq(&self) -> f32 {
+        self.loop_filter.integrator()
+    }
+
+    fn reset(&mut self) {
+        self.loop_filter.reset();
+        self.phase = 0.0;
+    }
+
+    fn set_bandwidth(&mut self, loop_bw: f32) {
+        self.loop_filter.set_bandwidth(loop_bw);
+
+        // Otherwise a disabled loop would go on rotating the samples at the
+        // frequency it had locked to.
+        if loop_bw == 0.0 {
+            self.reset();
+        }
+    }
+
+    /// Phase error scaled by the magnitude of the symbol, negative when the
+    /// symbol is ahead of the nearest constellation point.
+    fn calc_error(&self, s: Complex32) -> f32 {
+        let p = self.constellation.decide(s);
+
+        (p * s.conj()).im / p.norm()
+    }
+
+    fn process(&mut self, x: Complex32) -> Complex32 {
+        let ret = x * Complex32::from_polar(1.0, -self.phase);
+        let mag = ret.norm();
+
+        // The error is negative when the de-rotated symbol is still ahead of
+        // the constellation point, so it is flipped to give the phase
+        // remaining to correct.
+        if mag > f32::EPSILON {
+            self.phase += self.loop_filter.update(-self.calc_error(ret) / mag);
         } else {
-            -1.0
+            self.phase += self.freq();
         }
+
+        self.phase = (self.phase + PI).rem_euclid(2.0 * PI) - PI;
+
+        ret
     }
 
-    fn calc_error(s: Complex32) -> f32 {
-        (Self::threshold(s.im) * s.re) - (Self::threshold(s.re) * s.im)
+    #[message_handler]
+    async fn reset_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        self.reset();
+
+        Ok(Pmt::F32(self.freq()))
     }
 
-    pub fn new() -> Block {
-        let mut phase_comp: f32 = 0.0;
+    #[message_handler]
+    async fn freq_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Pmt::F32(freq) = p {
+            self.loop_filter.set_integrator(freq);
+        }
 
-        Apply::new(move |x: &Complex32| {
-            let (mag, mut phase) = x.to_polar();
+        Ok(Pmt::F32(self.freq()))
+    }
 
-            phase += phase_comp;
+    #[message_handler]
+    async fn bandwidth_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Pmt::F32(loop_bw) = p {
+            self.set_bandwidth(loop_bw);
+        }
+
+        Ok(Pmt::F32(self.freq()))
+    }
+}
 
-            let ret = Complex32::from_polar(mag, phase);
-            phase_comp += Self::calc_error(ret);
+#[async_trait]
+impl Kernel for CarrierSync {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        _mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let input = sio.input(0).slice::<Complex32>();
+        let output = sio.output(0).slice::<Complex32>();
+        let n = input.len().min(output.len());
 
-            while phase_comp > (2.0 * PI) {
-                phase_comp -= 2.0 * PI;
-            }
+        for (i, o) in input.iter().zip(output.iter_mut()) {
+            *o = self.process(*i);
+        }
+
+        if sio.input(0).finished() && n == input.len() {
+            io.finished = true;
+        }
 
-            while phase_comp < (-2.0 * PI) {
-                phase_comp += 2.0 * PI;
-            }
+        sio.input(0).consume(n);
+        sio.output(0).produce(n);
 
-            ret
-        })
+        Ok(())
     }
 }
 
@@ -46,22 +196,100 @@ mod tests {
     use anyhow::Result;
     use futuresdr::num_complex::Complex32;
 
+    use std::f32::consts::PI;
+
+    use crate::{
+        constellation::Modulation,
+        sym::{Sym, MAGNITUDE},
+    };
+
     use super::CarrierSync;
 
     #[test]
     fn error_fn() -> Result<()> {
-        assert_eq!(CarrierSync::calc_error(Complex32::new(1.0, 1.0)), 0.0);
-        assert_eq!(CarrierSync::calc_error(Complex32::new(-1.0, 1.0)), 0.0);
-        assert_eq!(CarrierSync::calc_error(Complex32::new(1.0, -1.0)), 0.0);
-        assert_eq!(CarrierSync::calc_error(Complex32::new(-1.0, -1.0)), 0.0);
-
-        assert!(CarrierSync::calc_error(Complex32::new(1.0, 1.1)) < 0.0);
-        assert!(CarrierSync::calc_error(Complex32::new(1.0, 0.9)) > 0.0);
-
-        assert_eq!(
-            CarrierSync::calc_error(Complex32::new(1.0, 1.1)).abs(),
-            CarrierSync::calc_error(Complex32::new(1.0, 0.9)).abs()
-        );
+        let cs = CarrierSync::create(0.02, 0.707, Modulation::Qpsk);
+
+        for sym in [Sym::A, Sym::B, Sym::C, Sym::D] {
+            assert_eq!(cs.calc_error(Complex32::from(&sym)), 0.0);
+        }
+
+        let ahead = cs.calc_error(Complex32::new(0.3, 0.33));
+        let behind = cs.calc_error(Complex32::new(0.3, 0.27));
+
+        assert!(ahead < 0.0);
+        assert!(behind > 0.0);
+        assert!((ahead + behind).abs() < 1e-6);
         Ok(())
     }
+
+    #[test]
+    fn error_fn_8psk() {
+        let cs = CarrierSync::create(0.02, 0.707, Modulation::Psk8);
+
+        // Points on the axes are tracked as well as the diagonal ones.
+        let x = Complex32::from_polar(MAGNITUDE, PI / 2.0 + 0.01);
+
+        assert!((cs.calc_error(x) / x.norm() + 0.01).abs() < 1e-4);
+    }
+
+    /// Run pseudo-random symbols rotating at `freq` radians per symbol
+    /// through the loop, returning the worst phase error of the last 100
+    /// symbols.
+    fn run(cs: &mut CarrierSync, phase: f32, freq: f32) -> f32 {
+        Sym::random(1000)
+            .iter()
+            .enumerate()
+            .map(|(n, sym)| {
+                let sym = Complex32::from(sym);
+                let rx = sym * Complex32::from_polar(1.0, phase + freq * n as f32);
+
+                (cs.process(rx) / sym).arg().abs()
+            })
+            .skip(900)
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn locks_phase() {
+        let mut cs = CarrierSync::create(0.02, 0.707, Modulation::Qpsk);
+
+        assert!(run(&mut cs, 0.5, 0.0) < 1e-3);
+        assert!(cs.freq().abs() < 1e-4);
+    }
+
+    #[test]
+    fn bypass() {
+        let mut cs = CarrierSync::create(0.0, 0.707, Modulation::Qpsk);
+        let x = Complex32::from_polar(MAGNITUDE, 0.3);
+
+        for _ in 0..100 {
+            assert_eq!(cs.process(x), x);
+        }
+    }
+
+    #[test]
+    fn disable_after_lock() {
+        let mut cs = CarrierSync::create(0.02, 0.707, Modulation::Qpsk);
+        let x = Complex32::from_polar(MAGNITUDE, 0.3);
+
+        run(&mut cs, 0.3, 0.02);
+        cs.set_bandwidth(0.0);
+
+        for _ in 0..100 {
+            assert_eq!(cs.process(x), x);
+        }
+    }
+
+    #[test]
+    fn tracks_frequency() {
+        let mut cs = CarrierSync::create(0.02, 0.707, Modulation::Qpsk);
+
+        // A first order loop would be left with a standing phase error.
+        assert!(run(&mut cs, 0.3, 0.02) < 1e-3);
+        assert!((cs.freq() - 0.02).abs() < 1e-4);
+
+        cs.reset();
+        assert!(run(&mut cs, -0.2, -0.01) < 1e-3);
+        assert!((cs.freq() + 0.01).abs() < 1e-4);
+    }
 }
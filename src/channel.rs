@@ -0,0 +1,364 @@
+use std::{collections::VecDeque, f32::consts::PI};
+
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    num_complex::Complex32,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
+use rand::{rngs::StdRng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+
+use crate::clock_sync::interpolate;
+
+/// Impairments added by the channel simulator.
+#[derive(Debug, Clone, PartialEq, clap::Args)]
+pub struct ChannelParams {
+    /// RMS magnitude of the complex white Gaussian noise added to every
+    /// sample.
+    #[arg(
+        long = "channel-noise",
+        default_value_t = 0.0,
+        value_parser = parse_non_negative
+    )]
+    pub noise: f32,
+    /// Carrier frequency offset, in Hz.
+    #[arg(long = "channel-freq-offset", default_value_t = 0.0)]
+    pub freq_offset: f32,
+    /// Standard deviation, in radians, of the step the carrier phase takes
+    /// each sample.
+    #[arg(
+        long = "channel-phase-noise",
+        default_value_t = 0.0,
+        value_parser = parse_non_negative
+    )]
+    pub phase_noise: f32,
+    /// Error of the transmitter's sample clock, in ppm, positive when it runs
+    /// fast.
+    #[arg(
+        long = "channel-drift",
+        default_value_t = 0.0,
+        value_parser = parse_drift
+    )]
+    pub drift_ppm: f32,
+    /// Complex gains of the paths of a tapped delay line with taps one sample
+    /// apart, starting with the direct path.
+    #[arg(
+        id = "channel_taps",
+        long = "channel-taps",
+        value_delimiter = ',',
+        default_value = "1",
+        value_parser = parse_tap
+    )]
+    pub taps: Vec<Complex32>,
+    /// Seed of the noise generators, the same seed always gives the same
+    /// impairments.
+    #[arg(long = "channel-seed", default_value_t = 0)]
+    pub seed: u64,
+}
+
+impl Default for ChannelParams {
+    fn default() -> Self {
+        Self {
+            noise: 0.0,
+            freq_offset: 0.0,
+            phase_noise: 0.0,
+            drift_ppm: 0.0,
+            taps: vec![Complex32::new(1.0, 0.0)],
+            seed: 0,
+        }
+    }
+}
+
+/// Parse a noise level, which can't be negative.
+fn parse_non_negative(s: &str) -> Result<f32, String> {
+    let x: f32 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if x >= 0.0 {
+        Ok(x)
+    } else {
+        Err("must not be negative".to_string())
+    }
+}
+
+/// Parse a clock drift, in ppm.  A drift of -1000000 ppm or less would stop
+/// the transmitter's clock altogether.
+fn parse_drift(s: &str) -> Result<f32, String> {
+    let x: f32 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if x > -1e6 && x.is_finite() {
+        Ok(x)
+    } else {
+        Err("must be more than -1000000 ppm".to_string())
+    }
+}
+
+/// Parse the gain of one path of the delay line.
+fn parse_tap(s: &str) -> Result<Complex32, String> {
+    if s.is_empty() {
+        return Err("a tap must not be empty".to_string());
+    }
+
+    s.parse().map_err(|e| format!("{e}"))
+}
+
+/// Channel simulator, for exercising the receiver without any hardware.  The
+/// signal passes through a tapped delay line, is resampled to simulate the
+/// drift between the sample clocks, rotated by the carrier frequency offset
+/// and phase noise, and finally has white Gaussian noise added.
+pub struct Channel {
+    taps: Vec<Complex32>,
+    /// Most recent input samples, newest first.
+    delay_line: VecDeque<Complex32>,
+    /// The four most recent samples from the delay line, oldest first.
+    history: [Complex32; 4],
+    /// Input samples consumed for every output sample.
+    step: f32,
+    /// Position of the next output sample relative to the second sample in
+    /// `history`, in samples.
+    next: f32,
+    /// Carrier frequency offset, in radians per sample.
+    freq: f32,
+    phase: f32,
+    rng: StdRng,
+    noise: Normal<f32>,
+    phase_noise: Normal<f32>,
+}
+
+impl Channel {
+    pub fn new(params: ChannelParams, sample_rate: f32) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("Channel").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .add_output("out", std::mem::size_of::<Complex32>())
+                .build(),
+            MessageIoBuilder::new().build(),
+            Self::create(params, sample_rate),
+        )
+    }
+
+    fn create(params: ChannelParams, sample_rate: f32) -> Self {
+        // Checked when the parameters are parsed.
+        assert!(!params.taps.is_empty() && params.drift_ppm > -1e6);
+
+        Channel {
+            delay_line: VecDeque::from(vec![Complex32::new(0.0, 0.0); params.taps.len()]),
+            taps: params.taps,
+            history: [Complex32::new(0.0, 0.0); 4],
+            step: 1.0 + params.drift_ppm * 1e-6,
+            next: 1.0,
+            freq: 2.0 * PI * params.freq_offset / sample_rate,
+            phase: 0.0,
+            rng: StdRng::seed_from_u64(params.seed),
+            noise: Normal::new(0.0, params.noise / std::f32::consts::SQRT_2).unwrap(),
+            phase_noise: Normal::new(0.0, params.phase_noise).unwrap(),
+        }
+    }
+
+    /// Rotate a resampled sample by the carrier phase and add the noise.
+    fn impair(&mut self, x: Complex32) -> Complex32 {
+        let noise = Complex32::new(
+            self.noise.sample(&mut self.rng),
+            self.noise.sample(&mut self.rng),
+        );
+        let ret = x * Complex32::from_polar(1.0, self.phase) + noise;
+
+        self.phase += self.freq + self.phase_noise.sample(&mut self.rng);
+        self.phase = (self.phase + PI).rem_euclid(2.0 * PI) - PI;
+
+        ret
+    }
+
+    fn push_samp(&mut self, x: Complex32, out: &mut Vec<Complex32>) {
+        self.delay_line.pop_back();
+        self.delay_line.push_front(x);
+
+        self.history.rotate_left(1);
+        self.history[3] = self
+            .delay_line
+            .iter()
+            .zip(self.taps.iter())
+            .map(|(x, t)| x * t)
+            .sum();
+        self.next -= 1.0;
+
+        while self.next < 1.0 {
+            let samp = interpolate(self.history, self.next.max(0.0));
+
+            out.push(self.impair(samp));
+            self.next += self.step;
+        }
+    }
+}
+
+#[async_trait]
+impl Kernel for Channel {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        _mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let is = sio.input(0).slice::<Complex32>();
+        let os = sio.output(0).slice::<Complex32>();
+        let mut consumed = 0;
+        let mut produced = 0;
+        let mut out = Vec::with_capacity(2);
+
+        for in_samp in is.iter() {
+            // A slightly slow transmitter clock means the occasional input
+            // sample produces two output samples.
+            if produced + 2 > os.len() {
+                break;
+            }
+
+            consumed += 1;
+            self.push_samp(*in_samp, &mut out);
+
+            for o in out.drain(..) {
+                os[produced] = o;
+                produced += 1;
+            }
+        }
+
+        if sio.input(0).finished() && consumed == is.len() {
+            io.finished = true;
+        }
+
+        sio.input(0).consume(consumed);
+        sio.output(0).produce(produced);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use futuresdr::num_complex::Complex32;
+
+    use super::{parse_drift, parse_non_negative, parse_tap, Channel, ChannelParams};
+
+    const SAMPLE_RATE: f32 = 800_000.0;
+
+    fn run(params: ChannelParams, input: &[Complex32]) -> Vec<Complex32> {
+        let mut channel = Channel::create(params, SAMPLE_RATE);
+        let mut out = Vec::new();
+
+        for x in input {
+            channel.push_samp(*x, &mut out);
+        }
+
+        out
+    }
+
+    fn tone(n: usize, freq: f32) -> Vec<Complex32> {
+        (0..n)
+            .map(|i| Complex32::from_polar(1.0, 2.0 * PI * freq * i as f32))
+            .collect()
+    }
+
+    #[test]
+    fn transparent() {
+        let input = tone(100, 0.01);
+
+        // The resampler delays the signal by two samples.
+        assert_eq!(run(ChannelParams::default(), &input)[2..], input[..98]);
+    }
+
+    #[test]
+    fn noise_power() {
+        let params = ChannelParams {
+            noise: 0.1,
+            ..ChannelParams::default()
+        };
+        let out = run(params, &vec![Complex32::new(0.0, 0.0); 100_000]);
+        let power = out.iter().map(|x| x.norm_sqr()).sum::<f32>() / out.len() as f32;
+
+        assert!((power - 0.01).abs() < 0.0005);
+    }
+
+    #[test]
+    fn seeded() {
+        let params = ChannelParams {
+            noise: 0.1,
+            phase_noise: 0.01,
+            ..ChannelParams::default()
+        };
+        let input = tone(1000, 0.01);
+
+        assert_eq!(run(params.clone(), &input), run(params.clone(), &input));
+        assert_ne!(
+            run(params.clone(), &input),
+            run(ChannelParams { seed: 1, ..params }, &input)
+        );
+    }
+
+    #[test]
+    fn frequency_offset() {
+        let params = ChannelParams {
+            freq_offset: 2_000.0,
+            ..ChannelParams::default()
+        };
+        let out = run(params, &vec![Complex32::new(1.0, 0.0); 1000]);
+        let w = 2.0 * PI * 2_000.0 / SAMPLE_RATE;
+
+        assert!(out[2..]
+            .windows(2)
+            .all(|x| ((x[1] / x[0]).arg() - w).abs() < 1e-4));
+    }
+
+    #[test]
+    fn clock_drift() {
+        for ppm in [-500.0, 200.0] {
+            let params = ChannelParams {
+                drift_ppm: ppm,
+                ..ChannelParams::default()
+            };
+            let out = run(params, &tone(100_000, 0.001));
+
+            // A fast transmitter clock leaves fewer samples at the receiver,
+            // and raises the frequency of the tone.
+            let expected = 100_000.0 / (1.0 + ppm * 1e-6);
+            assert!((out.len() as f32 - expected).abs() <= 2.0);
+
+            let step = out[10_000..90_000]
+                .windows(2)
+                .map(|x| (x[1] / x[0]).arg() as f64)
+                .sum::<f64>()
+                / 79_999.0;
+            let expected = 2.0 * std::f64::consts::PI * 0.001 * (1.0 + ppm as f64 * 1e-6);
+            assert!((step - expected).abs() < 2e-7);
+        }
+    }
+
+    #[test]
+    fn multipath() {
+        let taps = vec![Complex32::new(1.0, 0.0), Complex32::new(0.3, -0.2)];
+        let params = ChannelParams {
+            taps: taps.clone(),
+            ..ChannelParams::default()
+        };
+        let mut impulse = vec![Complex32::new(0.0, 0.0); 10];
+        impulse[0] = Complex32::new(1.0, 0.0);
+
+        assert_eq!(run(params, &impulse)[2..4], taps);
+    }
+
+    #[test]
+    fn bad_params() {
+        assert_eq!(parse_non_negative("0.1"), Ok(0.1));
+        assert!(parse_non_negative("-0.1").is_err());
+        assert_eq!(parse_drift("-999999"), Ok(-999_999.0));
+        assert!(parse_drift("-1000000").is_err());
+        assert!(parse_drift("-2e6").is_err());
+        assert_eq!(parse_tap("0.5-0.5i"), Ok(Complex32::new(0.5, -0.5)));
+        assert!(parse_tap("").is_err());
+    }
+}
@@ -8,17 +8,65 @@ use futuresdr::{
     },
 };
 
+use crate::loop_filter::LoopFilter;
+
+/// Slope of the normalised Gardner error (per symbol of timing error) around
+/// the lock point for raised cosine pulses.
+const DETECTOR_GAIN: f32 = 2.0;
+
+/// Largest timing adjustment made in a single symbol, as a fraction of a
+/// symbol.
+const MAX_ADJUST: f32 = 0.25;
+
+/// Limit on the normalised timing error fed into the loop.
+const MAX_ERROR: f32 = 1.0;
+
+/// Time constant, in symbols, of the signal power estimate used to normalise
+/// the timing error.
+const POWER_ALPHA: f32 = 0.01;
+
+/// Cubic Lagrange interpolation, in Farrow form, at `mu` (between 0 and 1)
+/// samples after the second of four consecutive samples.
+pub(crate) fn interpolate(history: [Complex32; 4], mu: f32) -> Complex32 {
+    let [xm1, x0, x1, x2] = history;
+
+    let c1 = -xm1 / 3.0 - x0 / 2.0 + x1 - x2 / 6.0;
+    let c2 = xm1 / 2.0 - x0 + x1 / 2.0;
+    let c3 = -xm1 / 6.0 + x0 / 2.0 - x1 / 2.0 + x2 / 6.0;
+
+    ((c3 * mu + c2) * mu + c1) * mu + x0
+}
+
+/// Symbol timing recovery using a Gardner timing error detector driving a
+/// second order loop.  Samples are taken at fractional positions between the
+/// input samples using a cubic Farrow interpolator, which allows the block to
+/// run at as little as two samples per symbol.
+///
+/// Two samples are produced for every symbol, the one half way from the
+/// previous symbol followed by the one at the centre of the symbol, for the
+/// fractionally spaced equaliser.
 pub struct ClockSync {
-    skip: i32,
-    n: i32,
-    err_gain: f32,
-    window: Vec<Complex32>,
+    sps: f32,
+    loop_filter: LoopFilter,
+    /// Current timing adjustment, as a fraction of a symbol.
+    adjust: f32,
+    /// The four most recent input samples, oldest first.  Interpolation takes
+    /// place between the second and third.
+    history: [Complex32; 4],
+    /// Position of the next interpolant relative to the second sample in
+    /// `history`, in samples.
+    next: f32,
+    /// Whether the next interpolant is a mid-symbol sample.
+    mid: bool,
+    prev: Complex32,
+    mid_samp: Complex32,
+    power: f32,
 }
 
 impl ClockSync {
-    pub fn new(sps: u32, err_gain: f32) -> Block {
-        assert!(sps > 3);
-
+    /// `loop_bw` is the loop noise bandwidth normalised to the symbol rate
+    /// and `damping` the loop damping factor.
+    pub fn new(sps: f32, loop_bw: f32, damping: f32) -> Block {
         Block::new(
             BlockMetaBuilder::new("ClockSync").build(),
             StreamIoBuilder::new()
@@ -26,55 +74,61 @@ impl ClockSync {
                 .add_output("out", std::mem::size_of::<Complex32>())
                 .build(),
             MessageIoBuilder::new().build(),
-            ClockSync {
-                skip: sps as i32,
-                n: sps as i32,
-                err_gain,
-                window: Vec::with_capacity(3),
-            },
+            Self::create(sps, loop_bw, damping),
         )
     }
 
-    fn calc_error(&mut self) -> f32 {
-        let max_delta = self.skip as f32 / 2.0;
+    fn create(sps: f32, loop_bw: f32, damping: f32) -> Self {
+        assert!(sps >= 2.0);
+
+        ClockSync {
+            sps,
+            loop_filter: LoopFilter::new(loop_bw, damping, DETECTOR_GAIN, MAX_ADJUST),
+            adjust: 0.0,
+            history: [Complex32::new(0.0, 0.0); 4],
+            next: 0.0,
+            mid: false,
+            prev: Complex32::new(0.0, 0.0),
+            mid_samp: Complex32::new(0.0, 0.0),
+            power: 0.0,
+        }
+    }
 
-        let x0 = self.window[0].re;
-        let x1 = self.window[1].re;
-        let x2 = self.window[2].re;
+    /// Gardner timing error, using both I and Q.  Positive when sampling
+    /// early, negative when sampling late.
+    fn calc_error(prev: Complex32, mid: Complex32, cur: Complex32) -> f32 {
+        (prev.re - cur.re) * mid.re + (prev.im - cur.im) * mid.im
+    }
 
-        self.window.clear();
+    fn push_samp(&mut self, s: Complex32, out: &mut Vec<Complex32>) {
+        self.history.rotate_left(1);
+        self.history[3] = s;
+        self.next -= 1.0;
 
-        let mut err = ((x2 - x0) * x1) * self.err_gain;
+        while self.next < 1.0 {
+            let samp = interpolate(self.history, self.next.max(0.0));
 
-        if err < -max_delta {
-            err = -max_delta
-        } else if err > max_delta {
-            err = max_delta;
-        }
+            if self.mid {
+                self.mid_samp = samp;
+            } else {
+                self.power += (samp.norm_sqr() - self.power) * POWER_ALPHA;
 
-        err
-    }
+                // Clamped so that the first few symbols, while the power
+                // estimate is still settling, can't throw the loop off.
+                let err = (Self::calc_error(self.prev, self.mid_samp, samp)
+                    / self.power.max(f32::EPSILON))
+                .clamp(-MAX_ERROR, MAX_ERROR);
 
-    fn push_samp(&mut self, s: Complex32) -> Option<Complex32> {
-        self.n -= 1;
+                self.adjust = self.loop_filter.update(err);
 
-        match self.n {
-            2 => {
-                self.window.push(s);
-                None
+                self.prev = samp;
+                out.push(self.mid_samp);
+                out.push(samp);
             }
-            1 => {
-                self.window.push(s);
-                Some(s)
-            }
-            0 => {
-                self.window.push(s);
-
-                self.n = self.skip + self.calc_error().round() as i32;
 
-                None
-            }
-            _ => None,
+            // The adjustment is spread over both halves of the next symbol.
+            self.mid = !self.mid;
+            self.next += self.sps / 2.0 * (1.0 + self.adjust);
         }
     }
 }
@@ -94,24 +148,28 @@ impl Kernel for ClockSync {
         let os = out_output.slice::<Complex32>();
         let mut consumed = 0;
         let mut produced = 0;
+        let mut out = Vec::with_capacity(2);
 
-        if sio.input(0).finished() {
-            io.finished = true;
-        }
+        for in_samp in is.iter() {
+            // Each input sample produces at most one symbol, and so two
+            // samples, when running at two or more samples per symbol.
+            if produced + 2 > os.len() {
+                break;
+            }
 
-        let mut in_iter = is.iter();
+            consumed += 1;
+            self.push_samp(*in_samp, &mut out);
 
-        for out_samp in os.iter_mut() {
-            'inner: for in_samp in &mut in_iter {
-                consumed += 1;
-                if let Some(o) = self.push_samp(*in_samp) {
-                    *out_samp = o;
-                    produced += 1;
-                    break 'inner;
-                }
+            for o in out.drain(..) {
+                os[produced] = o;
+                produced += 1;
             }
         }
 
+        if sio.input(0).finished() && consumed == is.len() {
+            io.finished = true;
+        }
+
         sio.input(0).consume(consumed);
         sio.output(0).produce(produced);
 
@@ -123,63 +181,102 @@ impl Kernel for ClockSync {
 mod tests {
     use futuresdr::num_complex::Complex32;
 
-    use super::ClockSync;
+    use crate::{pulse_shape::PulseShape, qam::QamMod, sym::Sym};
 
-    #[test]
-    fn err_convergence() {
-        let mut cs = ClockSync {
-            skip: 10,
-            err_gain: 20.0,
-            n: 0,
-            window: Vec::with_capacity(3),
-        };
+    use super::{interpolate, ClockSync};
 
-        // samples are heading towards the symbol above 0. Err should be +ve so
-        // we skip more samples.
-        cs.window.push(Complex32::new(0.1, 0.0));
-        cs.window.push(Complex32::new(0.2, 0.0));
-        cs.window.push(Complex32::new(0.3, 0.0));
+    #[test]
+    fn err_sign() {
+        let a = Complex32::new(0.3, 0.3);
+        let b = Complex32::new(-0.3, -0.3);
+
+        // Moving from A to D, an early mid-symbol sample still leans towards
+        // A and a late one towards D.
+        assert!(ClockSync::calc_error(a, a * 0.2, b) > 0.0);
+        assert!(ClockSync::calc_error(a, b * 0.2, b) < 0.0);
+        assert_eq!(ClockSync::calc_error(a, a * 0.0, b), 0.0);
+
+        // Sign doesn't depend on the direction of the transition.
+        assert!(ClockSync::calc_error(b, b * 0.2, a) > 0.0);
+        assert!(ClockSync::calc_error(b, a * 0.2, a) < 0.0);
+    }
 
-        assert!(cs.calc_error() > 0.0);
+    #[test]
+    fn interpolation() {
+        // Cubic interpolation is exact for a cubic.
+        let f = |t: f32| Complex32::new(t * t * t - 2.0 * t, t * t);
+        let history = [f(-1.0), f(0.0), f(1.0), f(2.0)];
 
-        // samples are heading away the symbol above 0. Err should be -ve so we
-        // skip less samples.
-        cs.window.push(Complex32::new(0.3, 0.0));
-        cs.window.push(Complex32::new(0.2, 0.0));
-        cs.window.push(Complex32::new(0.1, 0.0));
+        for mu in [0.0, 0.25, 0.5, 0.9] {
+            assert!((interpolate(history, mu) - f(mu)).norm() < 1e-5);
+        }
+    }
 
-        assert!(cs.calc_error() < 0.0);
+    /// Pseudo-random symbols, shaped and matched filtered, then resampled
+    /// with a timing offset (in samples) and a sample clock error.
+    fn rx_samples(sps: usize, offset: f32, clock_err: f32, n_syms: usize) -> Vec<Complex32> {
+        let pulse = PulseShape {
+            rrc: true,
+            ..PulseShape::default()
+        };
 
-        // samples are heading towards the symbol below 0. Err should be +ve so
-        // we skip more samples.
-        cs.window.push(Complex32::new(-0.1, 0.0));
-        cs.window.push(Complex32::new(-0.2, 0.0));
-        cs.window.push(Complex32::new(-0.3, 0.0));
+        let syms = Sym::random(n_syms);
+
+        // Oversample heavily so that picking the nearest sample is a good
+        // approximation of a fractional delay.
+        let os = 16;
+        let tx = QamMod::modulate((sps * os) as u16, pulse, &syms);
+        let rx_taps = pulse.rx_taps(sps * os);
+        let step = os as f32 * (1.0 + clock_err);
+
+        (0..)
+            .map(|n| (n as f32 * step + offset * os as f32).round() as usize)
+            .take_while(|i| *i < tx.len())
+            .map(|i| {
+                rx_taps
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j <= i)
+                    .map(|(j, t)| tx[i - j] * t)
+                    .sum()
+            })
+            .collect()
+    }
 
-        assert!(cs.calc_error() > 0.0);
+    fn run(sps: usize, offset: f32, clock_err: f32) {
+        let n_syms = 800;
+        let samples = rx_samples(sps, offset, clock_err, n_syms);
+        let mut cs = ClockSync::create(sps as f32, 0.01, 0.707);
+        let mut out = Vec::new();
 
-        // samples are heading towards the symbol below 0. Err should be -ve so
-        // we skip less samples.
-        cs.window.push(Complex32::new(-0.5, 0.0));
-        cs.window.push(Complex32::new(-0.3, 0.0));
-        cs.window.push(Complex32::new(-0.2, 0.0));
+        for s in samples.iter() {
+            cs.push_samp(*s, &mut out);
+        }
 
-        assert!(cs.calc_error() < 0.0);
+        // Once locked the centre of every symbol should sit close to a
+        // constellation point.
+        let centres: Vec<Complex32> = out.iter().skip(1).step_by(2).copied().collect();
+        let locked = &centres[centres.len() / 2..centres.len() - 10];
+        let worst = locked
+            .iter()
+            .map(|x| (x.re.abs() - 0.3).abs().max((x.im.abs() - 0.3).abs()))
+            .fold(0.0, f32::max);
 
-        // samples are at the symbol peak above 0. Err should be 0 so we don't
-        // induce any more or less skip.
-        cs.window.push(Complex32::new(0.3, 0.0));
-        cs.window.push(Complex32::new(0.4, 0.0));
-        cs.window.push(Complex32::new(0.3, 0.0));
+        assert!(worst < 0.06, "worst error {worst}");
+    }
 
-        assert_eq!(cs.calc_error(), 0.0);
+    #[test]
+    fn converges_10_sps() {
+        run(10, 3.7, 0.0);
+    }
 
-        // samples are at the symbol peak below 0. Err should be 0 so we don't induce
-        // any more or less skip.
-        cs.window.push(Complex32::new(-0.3, 0.0));
-        cs.window.push(Complex32::new(-0.4, 0.0));
-        cs.window.push(Complex32::new(-0.3, 0.0));
+    #[test]
+    fn converges_2_sps() {
+        run(2, 0.6, 0.0);
+    }
 
-        assert_eq!(cs.calc_error(), 0.0);
+    #[test]
+    fn tracks_clock_error() {
+        run(4, 1.3, 200e-6);
     }
 }
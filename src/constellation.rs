@@ -0,0 +1,271 @@
+use std::f32::consts::PI;
+
+use futuresdr::num_complex::Complex32;
+
+use crate::sym::MAGNITUDE;
+
+/// Modulation of the data carried in each frame.  Both ends of the link must
+/// agree on the modulation used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Modulation {
+    Bpsk,
+    Qpsk,
+    /// Differentially encoded QPSK: the bits of each symbol select the change
+    /// in phase from the previous symbol, so the data survives any rotation of
+    /// the constellation.
+    Dqpsk,
+    #[value(name = "8psk")]
+    Psk8,
+    #[value(name = "16qam")]
+    Qam16,
+    #[value(name = "64qam")]
+    Qam64,
+}
+
+impl Modulation {
+    pub fn constellation(&self) -> Box<dyn Constellation> {
+        match self {
+            Modulation::Bpsk => Box::new(Psk::new(1)),
+            Modulation::Qpsk | Modulation::Dqpsk => Box::new(SquareQam::new(2)),
+            Modulation::Psk8 => Box::new(Psk::new(3)),
+            Modulation::Qam16 => Box::new(SquareQam::new(4)),
+            Modulation::Qam64 => Box::new(SquareQam::new(6)),
+        }
+    }
+
+    pub fn is_differential(&self) -> bool {
+        *self == Modulation::Dqpsk
+    }
+
+    /// Number of equal rotations that leave the decision constellation
+    /// unchanged, any of which the carrier sync could lock to.
+    pub fn symmetry(&self) -> usize {
+        match self {
+            Modulation::Psk8 => 8,
+            _ => 4,
+        }
+    }
+
+    /// Constellation against which decision directed estimates are made
+    /// before the rotation of the constellation is known.  It contains the
+    /// QPSK points used by the sync words and frame size, and is unchanged by
+    /// a quarter turn: BPSK is the only modulation that isn't, and its points
+    /// are a subset of the QPSK ones.
+    pub fn decision_constellation(&self) -> Box<dyn Constellation> {
+        match self {
+            Modulation::Bpsk => Modulation::Qpsk.constellation(),
+            _ => self.constellation(),
+        }
+    }
+}
+
+/// A set of constellation points, each labelled with the bits it carries.
+/// Every constellation is scaled so that its outermost points have the same
+/// magnitude as the QPSK points, which is the level the AGC aims for.
+pub trait Constellation: Send {
+    fn bits_per_symbol(&self) -> usize;
+
+    /// The constellation points, indexed by the bits they carry (most
+    /// significant bit first).
+    fn points(&self) -> &[Complex32];
+
+    fn map(&self, bits: &[bool]) -> Complex32 {
+        assert_eq!(bits.len(), self.bits_per_symbol());
+
+        self.points()[bits.iter().fold(0, |acc, b| (acc << 1) | *b as usize)]
+    }
+
+    /// The point closest to `x`.
+    fn decide(&self, x: Complex32) -> Complex32 {
+        *self
+            .points()
+            .iter()
+            .min_by(|a, b| (x - *a).norm_sqr().total_cmp(&(x - *b).norm_sqr()))
+            .unwrap()
+    }
+
+    /// Max-log approximation of the log-likelihood ratio of each bit of `x`,
+    /// given the noise variance of each component.  Positive values represent
+    /// a `0` bit.
+    fn demap(&self, x: Complex32, noise_var: f32) -> Vec<f32> {
+        let dist: Vec<f32> = self.points().iter().map(|p| (x - p).norm_sqr()).collect();
+
+        (0..self.bits_per_symbol())
+            .rev()
+            .map(|bit| {
+                let (mut zero, mut one) = (f32::INFINITY, f32::INFINITY);
+
+                for (label, d) in dist.iter().enumerate() {
+                    if label >> bit & 1 == 1 {
+                        one = one.min(*d);
+                    } else {
+                        zero = zero.min(*d);
+                    }
+                }
+
+                (one - zero) / (2.0 * noise_var)
+            })
+            .collect()
+    }
+}
+
+fn gray(i: usize) -> usize {
+    i ^ (i >> 1)
+}
+
+/// Phase shift keying with Gray labelling around the circle.  The points sit
+/// on the diagonals used by QPSK, so BPSK can be tracked by the same carrier
+/// recovery.
+pub struct Psk {
+    bits: usize,
+    points: Vec<Complex32>,
+}
+
+impl Psk {
+    pub fn new(bits: usize) -> Self {
+        let m = 1 << bits;
+        let mut points = vec![Complex32::new(0.0, 0.0); m];
+
+        for i in 0..m {
+            let phase = PI / 4.0 + 2.0 * PI * i as f32 / m as f32;
+            points[gray(i)] = Complex32::from_polar(MAGNITUDE, phase);
+        }
+
+        Self { bits, points }
+    }
+}
+
+impl Constellation for Psk {
+    fn bits_per_symbol(&self) -> usize {
+        self.bits
+    }
+
+    fn points(&self) -> &[Complex32] {
+        &self.points
+    }
+}
+
+/// Square QAM built from two Gray labelled PAM constellations.  The first
+/// half of the bits select the quadrature component and the second half the
+/// in-phase one, which for two bits gives the mapping of `Sym`.
+pub struct SquareQam {
+    bits: usize,
+    points: Vec<Complex32>,
+}
+
+impl SquareQam {
+    pub fn new(bits: usize) -> Self {
+        assert_eq!(bits & 1, 0);
+
+        let levels = 1 << (bits / 2);
+        let step = MAGNITUDE / std::f32::consts::SQRT_2 / (levels - 1) as f32;
+
+        // The PAM level carrying each label, from the most positive down.
+        let mut pam = vec![0.0; levels];
+        for i in 0..levels {
+            pam[gray(i)] = (levels as f32 - 1.0 - 2.0 * i as f32) * step;
+        }
+
+        let points = (0..1 << bits)
+            .map(|label| Complex32::new(pam[label % levels], pam[label / levels]))
+            .collect();
+
+        Self { bits, points }
+    }
+}
+
+impl Constellation for SquareQam {
+    fn bits_per_symbol(&self) -> usize {
+        self.bits
+    }
+
+    fn points(&self) -> &[Complex32] {
+        &self.points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futuresdr::num_complex::Complex32;
+
+    use crate::sym::{Sym, MAGNITUDE};
+
+    use super::Modulation;
+
+    const ALL: [Modulation; 6] = [
+        Modulation::Bpsk,
+        Modulation::Qpsk,
+        Modulation::Dqpsk,
+        Modulation::Psk8,
+        Modulation::Qam16,
+        Modulation::Qam64,
+    ];
+
+    fn bits(label: usize, n: usize) -> Vec<bool> {
+        (0..n).rev().map(|i| label >> i & 1 == 1).collect()
+    }
+
+    #[test]
+    fn peak_magnitude() {
+        for m in ALL {
+            let c = m.constellation();
+            let peak = c.points().iter().map(|p| p.norm()).fold(0.0, f32::max);
+
+            assert_eq!(c.points().len(), 1 << c.bits_per_symbol());
+            assert!((peak - MAGNITUDE).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn qpsk_matches_sym() {
+        let c = Modulation::Qpsk.constellation();
+
+        for sym in [Sym::A, Sym::B, Sym::C, Sym::D] {
+            let n = u8::from(sym);
+
+            assert_eq!(c.map(&[n >> 1 == 1, n & 1 == 1]), Complex32::from(&sym));
+        }
+    }
+
+    #[test]
+    fn gray_labelling() {
+        for m in ALL {
+            let c = m.constellation();
+            let points = c.points();
+
+            // Each point's nearest neighbours differ from it in one bit.
+            for (i, p) in points.iter().enumerate() {
+                let nearest = points
+                    .iter()
+                    .filter(|q| *q != p)
+                    .map(|q| (p - q).norm())
+                    .fold(f32::INFINITY, f32::min);
+
+                for (j, q) in points.iter().enumerate() {
+                    if q != p && (p - q).norm() < nearest * 1.01 {
+                        assert_eq!((i ^ j).count_ones(), 1, "{m:?} {i} {j}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn demap() {
+        for m in ALL {
+            let c = m.constellation();
+            let n = c.bits_per_symbol();
+
+            for label in 0..1 << n {
+                let bits = bits(label, n);
+                let x = c.map(&bits) + Complex32::new(0.01, -0.01);
+
+                assert_eq!(c.decide(x), c.map(&bits));
+
+                let soft = c.demap(x, 0.01);
+                assert_eq!(soft.len(), n);
+                assert!(soft.iter().zip(&bits).all(|(s, b)| (*s < 0.0) == *b));
+            }
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    macros::message_handler,
+    num_complex::Complex32,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::header::Frame;
+
+/// Time constant, in samples, of the average power measured by the carrier
+/// sense.
+const POWER_ALPHA: f32 = 0.01;
+
+/// How far, in dB, the power must fall below the threshold before a busy
+/// channel is idle again.
+const HYSTERESIS_DB: f32 = 3.0;
+
+/// Number of frames queued while waiting for the channel, beyond which
+/// further frames are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// Settings of the CSMA/CA MAC, for sharing one frequency between several
+/// stations.
+#[derive(Debug, Clone, Copy, PartialEq, clap::Args)]
+pub struct CsmaParams {
+    /// Listen before transmitting, so that several stations can share one
+    /// frequency.
+    #[arg(id = "csma", long = "csma")]
+    pub enabled: bool,
+    /// Received power, in dB relative to full scale, above which the channel
+    /// is busy.
+    #[arg(long = "csma-threshold", default_value_t = -60.0)]
+    pub threshold: f32,
+    /// Length of a backoff slot, in microseconds.  The channel must also be
+    /// idle for a slot before the backoff starts.
+    #[arg(long = "csma-slot", default_value_t = 1000)]
+    pub slot_us: u64,
+    /// Probability of transmitting in each idle slot once the backoff has
+    /// run out.
+    #[arg(
+        long = "csma-persistence",
+        default_value_t = 0.5,
+        value_parser = parse_persistence
+    )]
+    pub persistence: f32,
+    /// Number of slots the random backoff is drawn from.
+    #[arg(
+        id = "csma_window",
+        long = "csma-window",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub window: u32,
+}
+
+impl Default for CsmaParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: -60.0,
+            slot_us: 1000,
+            persistence: 0.5,
+            window: 16,
+        }
+    }
+}
+
+/// A persistence must be a probability, and more than zero.
+fn parse_persistence(s: &str) -> Result<f32, String> {
+    let p: f32 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if p > 0.0 && p <= 1.0 {
+        Ok(p)
+    } else {
+        Err("must be more than 0 and no more than 1".to_string())
+    }
+}
+
+/// Energy detecting carrier sense, which passes the received samples straight
+/// through and posts `U32(1)` to the `busy` message output when their average
+/// power rises above the threshold, and `U32(0)` once it falls back below it.
+/// Any message on `rssi` responds with the average power, in dB relative to
+/// full scale, as an `F32`.
+pub struct CarrierSense {
+    /// Power above which the channel is busy.
+    busy_level: f32,
+    /// Power below which a busy channel is idle again.
+    idle_level: f32,
+    power: f32,
+    busy: bool,
+}
+
+impl CarrierSense {
+    /// `threshold` is in dB relative to full scale.
+    pub fn new(threshold: f32) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("CarrierSense").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .add_output("out", std::mem::size_of::<Complex32>())
+                .build(),
+            MessageIoBuilder::new()
+                .add_input("rssi", Self::rssi_handler)
+                .add_output("busy")
+                .build(),
+            Self::create(threshold),
+        )
+    }
+
+    fn create(threshold: f32) -> Self {
+        CarrierSense {
+            busy_level: 10f32.powf(threshold / 10.0),
+            idle_level: 10f32.powf((threshold - HYSTERESIS_DB) / 10.0),
+            power: 0.0,
+            busy: false,
+        }
+    }
+
+    fn rssi(&self) -> f32 {
+        10.0 * self.power.log10()
+    }
+
+    /// Push the next sample, returning whether the channel has just become
+    /// busy or idle.
+    fn push_samp(&mut self, x: Complex32) -> Option<bool> {
+        self.power += (x.norm_sqr() - self.power) * POWER_ALPHA;
+
+        let busy = if self.busy {
+            self.power >= self.idle_level
+        } else {
+            self.power > self.busy_level
+        };
+
+        (busy != self.busy).then(|| {
+            self.busy = busy;
+            busy
+        })
+    }
+
+    #[message_handler]
+    async fn rssi_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        Ok(Pmt::F32(self.rssi()))
+    }
+}
+
+#[async_trait]
+impl Kernel for CarrierSense {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let input = sio.input(0).slice::<Complex32>();
+        let output = sio.output(0).slice::<Complex32>();
+        let n = input.len().min(output.len());
+
+        for (i, x) in input[..n].iter().enumerate() {
+            output[i] = *x;
+
+            if let Some(busy) = self.push_samp(*x) {
+                mio.post(0, Pmt::U32(busy as u32)).await;
+            }
+        }
+
+        if sio.input(0).finished() && n == input.len() {
+            io.finished = true;
+        }
+
+        sio.input(0).consume(n);
+        sio.output(0).produce(n);
+
+        Ok(())
+    }
+}
+
+/// Carrier sense multiple access with collision avoidance.  Frames posted to
+/// `in` are queued until the channel is free and then posted to `out`, for the
+/// frame encoder, one at a time.
+///
+/// The channel is busy while the carrier sense posts a `U32(1)` to `carrier`,
+/// the frame decoder a `U32(1)` to `decoding`, or from when a frame is sent
+/// until the frame encoder posts a `U32(0)` to `sending`.  Once it has been
+/// idle for a slot the random backoff is counted down, a slot at a time and
+/// frozen whenever the channel is busy, after which each idle slot the frame
+/// is sent with the given persistence.  A fresh backoff is drawn after every
+/// frame sent, and when the channel turns busy while a frame is waiting.
+///
+/// When disabled frames are passed straight through.
+pub struct Csma {
+    params: CsmaParams,
+    slot: Duration,
+    rng: StdRng,
+    queue: VecDeque<Frame>,
+    carrier: bool,
+    decoding: bool,
+    sending: bool,
+    /// When the channel last became idle, if it has ever been busy.
+    idle_since: Option<Instant>,
+    /// End of the current slot, while waiting for an idle channel.
+    next_slot: Option<Instant>,
+    /// Idle slots to wait before the frame may be sent.
+    backoff: u32,
+    dropped: usize,
+}
+
+impl Csma {
+    pub fn new(params: CsmaParams) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("Csma").build(),
+            StreamIoBuilder::new().build(),
+            MessageIoBuilder::new()
+                .add_input("in", Self::frame_handler)
+                .add_input("carrier", Self::carrier_handler)
+                .add_input("decoding", Self::decoding_handler)
+                .add_input("sending", Self::sending_handler)
+                .add_output("out")
+                .build(),
+            Self::create(params, StdRng::from_entropy()),
+        )
+    }
+
+    fn create(params: CsmaParams, rng: StdRng) -> Self {
+        assert!(params.persistence > 0.0 && params.persistence <= 1.0);
+        assert!(params.window > 0);
+
+        Csma {
+            params,
+            slot: Duration::from_micros(params.slot_us),
+            rng,
+            queue: VecDeque::new(),
+            carrier: false,
+            decoding: false,
+            sending: false,
+            idle_since: None,
+            next_slot: None,
+            backoff: 0,
+            dropped: 0,
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.carrier || self.decoding || self.sending
+    }
+
+    fn draw_backoff(&mut self) {
+        self.backoff = self.rng.gen_range(0..self.params.window);
+    }
+
+    /// Update what the channel is doing, noting when it turns busy or idle.
+    fn update(&mut self, now: Instant, f: impl FnOnce(&mut Self)) {
+        let was_busy = self.busy();
+
+        f(self);
+
+        match (was_busy, self.busy()) {
+            (true, false) => self.idle_since = Some(now),
+            (false, true) => {
+                self.next_slot = None;
+
+                if !self.queue.is_empty() && self.backoff == 0 {
+                    self.draw_backoff();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn push_frame(&mut self, frame: Frame) {
+        if self.queue.len() == QUEUE_LEN {
+            self.dropped += 1;
+            eprintln!(
+                "Dropping frame waiting for the channel ({} dropped so far)",
+                self.dropped
+            );
+            return;
+        }
+
+        self.queue.push_back(frame);
+    }
+
+    /// Run the slots that have ended by `now`, returning the frame to send
+    /// should one of them win the channel.
+    fn poll(&mut self, now: Instant) -> Option<Frame> {
+        if !self.params.enabled {
+            return self.queue.pop_front();
+        }
+
+        loop {
+            if self.queue.is_empty() || self.busy() {
+                self.next_slot = None;
+                return None;
+            }
+
+            // The first slot ends a slot after the channel became idle, or
+            // straight away if it has been idle for longer.
+            let first = self.idle_since.map_or(now, |t| (t + self.slot).max(now));
+            let slot = *self.next_slot.get_or_insert(first);
+
+            if slot > now {
+                return None;
+            }
+
+            self.next_slot = Some(slot + self.slot);
+
+            if self.backoff > 0 {
+                self.backoff -= 1;
+            } else if self.rng.gen::<f32>() < self.params.persistence {
+                self.update(now, |s| s.sending = true);
+                self.draw_backoff();
+                return self.queue.pop_front();
+            }
+        }
+    }
+
+    /// Parse a busy indication, as posted by the carrier sense, frame decoder
+    /// and frame encoder.
+    fn busy_pmt(p: &Pmt) -> Option<bool> {
+        match p {
+            Pmt::U32(busy) => Some(*busy != 0),
+            _ => None,
+        }
+    }
+
+    #[message_handler]
+    async fn frame_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Some(frame) = Frame::from_pmt(&p) {
+            self.push_frame(frame);
+        }
+
+        Ok(Pmt::Null)
+    }
+
+    #[message_handler]
+    async fn carrier_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Some(busy) = Self::busy_pmt(&p) {
+            self.update(Instant::now(), |s| s.carrier = busy);
+        }
+
+        Ok(Pmt::Null)
+    }
+
+    #[message_handler]
+    async fn decoding_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Some(busy) = Self::busy_pmt(&p) {
+            self.update(Instant::now(), |s| s.decoding = busy);
+        }
+
+        Ok(Pmt::Null)
+    }
+
+    #[message_handler]
+    async fn sending_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        // Only the end of a frame is of interest, the channel is already
+        // taken to be busy from when the frame was sent to the encoder.
+        if Self::busy_pmt(&p) == Some(false) {
+            self.update(Instant::now(), |s| s.sending = false);
+        }
+
+        Ok(Pmt::Null)
+    }
+}
+
+#[async_trait]
+impl Kernel for Csma {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        _sio: &mut StreamIo,
+        mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        while let Some(frame) = self.poll(Instant::now()) {
+            mio.post(0, frame.to_pmt()).await;
+        }
+
+        // Called again at the end of the slot, or sooner when a message
+        // arrives.
+        if let Some(slot) = self.next_slot {
+            io.block_on(async move {
+                async_io::Timer::at(slot).await;
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futuresdr::num_complex::Complex32;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::header::{Frame, FrameType};
+
+    use super::{CarrierSense, Csma, CsmaParams, QUEUE_LEN};
+
+    const PARAMS: CsmaParams = CsmaParams {
+        enabled: true,
+        threshold: -60.0,
+        slot_us: 1000,
+        persistence: 1.0,
+        window: 16,
+    };
+
+    const SLOT: Duration = Duration::from_millis(1);
+
+    fn csma(params: CsmaParams) -> Csma {
+        Csma::create(params, StdRng::seed_from_u64(1))
+    }
+
+    fn frame(seq: u16) -> Frame {
+        Frame {
+            frame_type: FrameType::Data,
+            seq,
+            flags: 0,
+            src: 0,
+            payload: vec![0xde, 0xad, 0xbe, 0xef],
+            quality: None,
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut csma = csma(CsmaParams::default());
+        let now = Instant::now();
+
+        csma.update(now, |s| s.carrier = true);
+        csma.push_frame(frame(0));
+        csma.push_frame(frame(1));
+
+        assert_eq!(csma.poll(now), Some(frame(0)));
+        assert_eq!(csma.poll(now), Some(frame(1)));
+        assert_eq!(csma.poll(now), None);
+    }
+
+    #[test]
+    fn idle_channel() {
+        let mut csma = csma(PARAMS);
+        let now = Instant::now();
+
+        csma.push_frame(frame(0));
+        csma.push_frame(frame(1));
+
+        // The first frame goes straight out, but the second must wait for it
+        // to be sent, then for a slot and the backoff.
+        assert_eq!(csma.poll(now), Some(frame(0)));
+        assert_eq!(csma.poll(now), None);
+        assert_eq!(csma.next_slot, None);
+
+        let backoff = csma.backoff;
+        let sent = now + Duration::from_millis(20);
+        csma.update(sent, |s| s.sending = false);
+        assert_eq!(csma.poll(sent), None);
+
+        let due = sent + SLOT * (backoff + 1);
+        assert_eq!(csma.poll(due - Duration::from_micros(1)), None);
+        assert_eq!(csma.poll(due), Some(frame(1)));
+    }
+
+    #[test]
+    fn defers_while_busy() {
+        let mut csma = csma(PARAMS);
+        let now = Instant::now();
+
+        csma.update(now, |s| s.carrier = true);
+        csma.push_frame(frame(0));
+        assert_eq!(csma.poll(now), None);
+        assert_eq!(csma.next_slot, None);
+
+        // A frame being decoded holds off the transmission even once the
+        // carrier has gone.
+        csma.update(now, |s| s.decoding = true);
+        csma.update(now + SLOT, |s| s.carrier = false);
+        assert_eq!(csma.poll(now + SLOT * 100), None);
+
+        let idle = now + SLOT * 100;
+        csma.update(idle, |s| s.decoding = false);
+        csma.backoff = 3;
+
+        assert_eq!(csma.poll(idle), None);
+        assert_eq!(csma.poll(idle + SLOT * 3), None);
+        assert_eq!(csma.next_slot, Some(idle + SLOT * 4));
+        assert_eq!(csma.poll(idle + SLOT * 4), Some(frame(0)));
+    }
+
+    #[test]
+    fn backoff_frozen() {
+        let mut csma = csma(PARAMS);
+        let now = Instant::now();
+
+        csma.update(now, |s| s.carrier = true);
+        csma.push_frame(frame(0));
+        csma.update(now, |s| s.carrier = false);
+        csma.backoff = 5;
+        assert_eq!(csma.poll(now), None);
+
+        // Three slots of the backoff pass before the channel turns busy
+        // again, and the rest are counted once it has been idle for a slot.
+        assert_eq!(csma.poll(now + SLOT * 3), None);
+        assert_eq!(csma.backoff, 2);
+
+        let busy = now + SLOT * 3 + SLOT / 2;
+        csma.update(busy, |s| s.carrier = true);
+        assert_eq!(csma.poll(busy + SLOT * 10), None);
+        assert_eq!(csma.backoff, 2);
+
+        let idle = busy + SLOT * 10;
+        csma.update(idle, |s| s.carrier = false);
+        assert_eq!(csma.poll(idle), None);
+        assert_eq!(csma.poll(idle + SLOT * 3 - Duration::from_micros(1)), None);
+        assert_eq!(csma.poll(idle + SLOT * 3), Some(frame(0)));
+    }
+
+    #[test]
+    fn persistence() {
+        let mut csma = csma(CsmaParams {
+            persistence: 0.25,
+            ..PARAMS
+        });
+        let start = Instant::now();
+        let mut first_slot = 0;
+
+        for i in 0..10_000 {
+            let now = start + SLOT * 1000 * i;
+
+            csma.push_frame(frame(0));
+            csma.backoff = 0;
+
+            if csma.poll(now).is_some() {
+                first_slot += 1;
+            }
+
+            // Run the slots until the frame is sent, then finish sending it.
+            let mut t = now;
+            while !csma.queue.is_empty() {
+                t += SLOT;
+                csma.poll(t);
+            }
+            csma.update(t, |s| s.sending = false);
+        }
+
+        assert!((2300..2700).contains(&first_slot), "{first_slot}");
+    }
+
+    #[test]
+    fn queue_full() {
+        let mut csma = csma(PARAMS);
+
+        csma.update(Instant::now(), |s| s.carrier = true);
+
+        for i in 0..QUEUE_LEN + 2 {
+            csma.push_frame(frame(i as u16));
+        }
+
+        assert_eq!(csma.queue.len(), QUEUE_LEN);
+        assert_eq!(csma.dropped, 2);
+    }
+
+    #[test]
+    fn carrier_sense() {
+        let mut cs = CarrierSense::create(-30.0);
+        let mut run = |amplitude: f32| {
+            (0..2000)
+                .filter_map(|_| cs.push_samp(Complex32::new(amplitude, 0.0)))
+                .collect::<Vec<_>>()
+        };
+
+        // 0.01 is -40 dBFS, and 0.1 is -20 dBFS.  Between the threshold and
+        // the hysteresis below it the channel stays as it was.
+        assert!(run(0.01).is_empty());
+        assert_eq!(run(0.1), [true]);
+        assert!(run(0.03).is_empty());
+        assert_eq!(run(0.01), [false]);
+        assert!(run(0.03).is_empty());
+    }
+
+    #[test]
+    fn rssi() {
+        let mut cs = CarrierSense::create(-30.0);
+
+        for _ in 0..2000 {
+            cs.push_samp(Complex32::from_polar(0.1, 1.0));
+        }
+
+        assert!((cs.rssi() + 20.0).abs() < 0.01);
+    }
+}
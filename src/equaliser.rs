@@ -0,0 +1,391 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    macros::message_handler,
+    num_complex::Complex32,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
+
+use crate::constellation::{Constellation, Modulation};
+use crate::qam::{LEVEL_ALPHA, SQUELCH};
+use crate::sym::MAGNITUDE;
+
+/// Time constant, in symbols, of the average dispersion used to decide
+/// whether the equaliser has locked.
+const LOCK_ALPHA: f32 = 0.01;
+
+/// Dispersion, over and above that of the constellation itself and relative
+/// to the square of the constant modulus, below which the equaliser is
+/// considered locked.
+const LOCK_THRESHOLD: f32 = 0.05;
+
+/// Dispersion above which a locked equaliser falls back to CMA.
+const UNLOCK_THRESHOLD: f32 = 0.15;
+
+/// Settings of the adaptive equaliser.
+#[derive(Debug, Clone, Copy, PartialEq, clap::Args)]
+pub struct EqualiserParams {
+    /// Number of taps, spaced half a symbol apart.
+    #[arg(
+        long = "eq-taps",
+        default_value_t = 15,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub taps: usize,
+    /// Step size of the tap updates, normalised to the power the AGC brings
+    /// the signal in the taps up to.  A step size of zero disables the
+    /// equaliser.
+    #[arg(long = "eq-step", default_value_t = 0.05)]
+    pub step: f32,
+}
+
+impl Default for EqualiserParams {
+    fn default() -> Self {
+        Self {
+            taps: 15,
+            step: 0.05,
+        }
+    }
+}
+
+/// Fractionally spaced adaptive equaliser, which takes the two samples per
+/// symbol produced by the clock sync and removes the inter-symbol
+/// interference caused by multipath, producing one sample per symbol.
+///
+/// The taps are adapted blindly using the constant modulus algorithm until
+/// the dispersion of the magnitude of the symbols settles down to that of the
+/// constellation, after which they are adapted with decision directed LMS
+/// against the modulation's decision constellation.  Adaptation stops while
+/// the signal is squelched.
+///
+/// Any message on the `taps` port responds with the current taps, as a
+/// `VecF32` of interleaved real and imaginary parts, oldest sample first, and
+/// any message on `reset` resets the taps and returns to CMA.
+pub struct Equaliser {
+    constellation: Box<dyn Constellation>,
+    /// Step size, normalised by the power of the signal in the taps.
+    step: f32,
+    /// Taps applied to `delay_line`, newest sample first.
+    taps: Vec<Complex32>,
+    /// The most recent input samples, newest first.
+    delay_line: VecDeque<Complex32>,
+    /// Whether the next input sample is a mid-symbol sample.
+    mid: bool,
+    /// Constant modulus the CMA drives the square of the magnitude of the
+    /// symbols towards.
+    modulus: f32,
+    /// Dispersion of the constellation about `modulus`, the least the CMA
+    /// can achieve.
+    floor: f32,
+    /// Average dispersion of the equalised symbols.
+    dispersion: f32,
+    locked: bool,
+    /// Average magnitude of the last few symbols.
+    level: f32,
+}
+
+impl Equaliser {
+    pub fn new(params: EqualiserParams, modulation: Modulation) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("Equaliser").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .add_output("out", std::mem::size_of::<Complex32>())
+                .build(),
+            MessageIoBuilder::new()
+                .add_input("taps", Self::taps_handler)
+                .add_input("reset", Self::reset_handler)
+                .build(),
+            Self::create(params, modulation),
+        )
+    }
+
+    fn create(params: EqualiserParams, modulation: Modulation) -> Self {
+        assert!(params.taps > 0);
+
+        let points = modulation.constellation().points().to_vec();
+        let mean = |f: &dyn Fn(f32) -> f32| {
+            points.iter().map(|p| f(p.norm_sqr())).sum::<f32>() / points.len() as f32
+        };
+        let power = mean(&|x| x);
+        let modulus = mean(&|x| x * x) / power;
+        let floor = mean(&|x| (x - modulus) * (x - modulus));
+
+        let mut ret = Equaliser {
+            constellation: modulation.decision_constellation(),
+            // Normalising by the power actually in the taps would let the
+            // step size grow without bound as a frame dies away.
+            step: params.step / (params.taps as f32 * power),
+            taps: Vec::new(),
+            delay_line: VecDeque::from(vec![Complex32::new(0.0, 0.0); params.taps]),
+            mid: true,
+            modulus,
+            floor,
+            dispersion: modulus * modulus,
+            locked: false,
+            level: 0.0,
+        };
+
+        ret.reset();
+        ret
+    }
+
+    /// Return to a pass-through filter, whose only tap picks out the centre
+    /// of a symbol, and to CMA.
+    fn reset(&mut self) {
+        let n = self.delay_line.len();
+
+        self.taps = vec![Complex32::new(0.0, 0.0); n];
+        // Taps with an even index line up with the centre of a symbol.
+        self.taps[((n - 1) / 2) & !1] = Complex32::new(1.0, 0.0);
+        self.dispersion = self.modulus * self.modulus;
+        self.locked = false;
+    }
+
+    fn taps_pmt(&self) -> Pmt {
+        Pmt::VecF32(self.taps.iter().rev().flat_map(|t| [t.re, t.im]).collect())
+    }
+
+    /// Feed in the next sample, returning the equalised symbol once the
+    /// centre of a symbol has been received.
+    fn process(&mut self, x: Complex32) -> Option<Complex32> {
+        self.delay_line.pop_back();
+        self.delay_line.push_front(x);
+
+        let centre = !self.mid;
+        self.mid = centre;
+
+        if !centre {
+            return None;
+        }
+
+        let y: Complex32 = self
+            .delay_line
+            .iter()
+            .zip(self.taps.iter())
+            .map(|(x, t)| x * t)
+            .sum();
+
+        self.level += (y.norm() - self.level) * LEVEL_ALPHA;
+
+        if self.step == 0.0 || self.level < SQUELCH * MAGNITUDE {
+            return Some(y);
+        }
+
+        let d = y.norm_sqr() - self.modulus;
+        self.dispersion += ((d * d - self.floor) - self.dispersion) * LOCK_ALPHA;
+
+        let threshold = if self.locked {
+            UNLOCK_THRESHOLD
+        } else {
+            LOCK_THRESHOLD
+        };
+        self.locked = self.dispersion < threshold * self.modulus * self.modulus;
+
+        let err = if self.locked {
+            y - self.constellation.decide(y)
+        } else {
+            y * d / self.modulus
+        };
+
+        for (t, x) in self.taps.iter_mut().zip(self.delay_line.iter()) {
+            *t -= err * x.conj() * self.step;
+        }
+
+        Some(y)
+    }
+
+    #[message_handler]
+    async fn taps_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        Ok(self.taps_pmt())
+    }
+
+    #[message_handler]
+    async fn reset_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        self.reset();
+
+        Ok(self.taps_pmt())
+    }
+}
+
+#[async_trait]
+impl Kernel for Equaliser {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        _mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let input = sio.input(0).slice::<Complex32>();
+        let output = sio.output(0).slice::<Complex32>();
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        for x in input.iter() {
+            if produced == output.len() {
+                break;
+            }
+
+            consumed += 1;
+
+            if let Some(y) = self.process(*x) {
+                output[produced] = y;
+                produced += 1;
+            }
+        }
+
+        if sio.input(0).finished() && consumed == input.len() {
+            io.finished = true;
+        }
+
+        sio.input(0).consume(consumed);
+        sio.output(0).produce(produced);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futuresdr::num_complex::Complex32;
+    use futuresdr::runtime::Pmt;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{constellation::Modulation, pulse_shape::PulseShape};
+
+    use super::{Equaliser, EqualiserParams};
+
+    /// Symbols of `modulation` sent through a multipath channel, sampled
+    /// twice per symbol with the centre of each symbol second.
+    fn rx_samples(modulation: Modulation, n_syms: usize, echo: Complex32) -> Vec<Complex32> {
+        let pulse = PulseShape {
+            rrc: true,
+            ..PulseShape::default()
+        };
+        let points = modulation.constellation().points().to_vec();
+        let mut rng = StdRng::seed_from_u64(1);
+        let syms: Vec<Complex32> = (0..n_syms)
+            .map(|_| points[rng.gen_range(0..points.len())])
+            .collect();
+
+        let tx = shaped(&syms, pulse);
+        let rx_taps = pulse.rx_taps(2);
+        let delay = rx_taps.len() - 1;
+
+        let filtered: Vec<Complex32> = (0..tx.len())
+            .map(|i| {
+                rx_taps
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j <= i)
+                    .map(|(j, t)| tx[i - j] * t)
+                    .sum()
+            })
+            .collect();
+
+        // The echo arrives a symbol and a half after the direct path.
+        let received: Vec<Complex32> = (0..filtered.len())
+            .map(|i| filtered[i] + i.checked_sub(3).map_or(0.0.into(), |j| filtered[j] * echo))
+            .collect();
+
+        received[(delay + 1) % 2..].to_vec()
+    }
+
+    /// Pulse shape the symbols at two samples per symbol.
+    fn shaped(syms: &[Complex32], pulse: PulseShape) -> Vec<Complex32> {
+        let taps = pulse.tx_taps(2);
+        let mut out = vec![Complex32::new(0.0, 0.0); (syms.len() + taps.len()) * 2];
+
+        for (i, s) in syms.iter().enumerate() {
+            for (j, t) in taps.iter().enumerate() {
+                out[i * 2 + j] += s * t;
+            }
+        }
+
+        out
+    }
+
+    fn run(modulation: Modulation, params: EqualiserParams, echo: Complex32) -> (Equaliser, f32) {
+        let samples = rx_samples(modulation, 6000, echo);
+        let mut eq = Equaliser::create(params, modulation);
+        let constellation = modulation.decision_constellation();
+        let out: Vec<Complex32> = samples.iter().filter_map(|x| eq.process(*x)).collect();
+
+        // Once converged every symbol should sit close to a constellation
+        // point.
+        let settled = &out[out.len() - 1000..out.len() - 20];
+        let worst = settled
+            .iter()
+            .map(|y| (y - constellation.decide(*y)).norm())
+            .fold(0.0, f32::max);
+
+        (eq, worst)
+    }
+
+    #[test]
+    fn removes_isi() {
+        let echo = Complex32::new(0.3, 0.15);
+
+        for modulation in [Modulation::Qpsk, Modulation::Qam16, Modulation::Qam64] {
+            let (_, before) = run(
+                modulation,
+                EqualiserParams {
+                    step: 0.0,
+                    ..EqualiserParams::default()
+                },
+                echo,
+            );
+            let (eq, after) = run(modulation, EqualiserParams::default(), echo);
+
+            assert!(before > 0.1, "{modulation:?} {before}");
+            assert!(after < 0.03, "{modulation:?} {after}");
+            assert!(eq.locked);
+        }
+    }
+
+    #[test]
+    fn bypass() {
+        let mut eq = Equaliser::create(
+            EqualiserParams {
+                step: 0.0,
+                ..EqualiserParams::default()
+            },
+            Modulation::Qpsk,
+        );
+        let input: Vec<Complex32> = (0..100).map(|i| Complex32::new(i as f32, 0.0)).collect();
+        let out: Vec<Complex32> = input.iter().filter_map(|x| eq.process(*x)).collect();
+
+        // Only the centre of each symbol is passed through, three symbols
+        // late.
+        assert_eq!(out.len(), 50);
+        assert_eq!(
+            out[3..],
+            input[1..94].iter().step_by(2).copied().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn taps_pmt() {
+        let eq = Equaliser::create(EqualiserParams::default(), Modulation::Qpsk);
+        let mut expected = vec![0.0; 30];
+        expected[16] = 1.0;
+
+        assert_eq!(eq.taps_pmt(), Pmt::VecF32(expected));
+    }
+}
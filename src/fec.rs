@@ -0,0 +1,273 @@
+/// Rate 1/2, K=7 convolutional code with the industry standard (133, 171)
+/// octal generator polynomials.  Higher rates are obtained by puncturing the
+/// mother code.
+const K: usize = 7;
+const N_STATES: usize = 1 << (K - 1);
+const G0: u8 = 0o133;
+const G1: u8 = 0o171;
+
+/// Unpack bytes into bits, most significant bit first.
+pub(crate) fn bits_from_bytes(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+        .collect()
+}
+
+/// Pack bits, most significant bit first, into bytes.  Trailing bits that do
+/// not make up a whole byte are discarded.
+pub(crate) fn bytes_from_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks_exact(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | *bit as u8))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CodeRate {
+    #[value(name = "none")]
+    Uncoded,
+    #[value(name = "1/2")]
+    Half,
+    #[value(name = "2/3")]
+    TwoThirds,
+    #[value(name = "3/4")]
+    ThreeQuarters,
+}
+
+impl CodeRate {
+    /// Which of the two mother code outputs are transmitted for each input
+    /// bit of the puncturing period.
+    fn puncture(&self) -> &'static [[bool; 2]] {
+        match self {
+            CodeRate::Uncoded => unreachable!(),
+            CodeRate::Half => &[[true, true]],
+            CodeRate::TwoThirds => &[[true, true], [true, false]],
+            CodeRate::ThreeQuarters => &[[true, true], [true, false], [false, true]],
+        }
+    }
+
+    /// Number of bits that will be transmitted for a block of `n_bytes`
+    /// bytes, including the tail bits used to terminate the trellis.
+    pub fn coded_len(&self, n_bytes: usize) -> usize {
+        if *self == CodeRate::Uncoded {
+            return n_bytes * 8;
+        }
+
+        let pattern = self.puncture();
+
+        (0..n_bytes * 8 + K - 1)
+            .map(|i| pattern[i % pattern.len()].iter().filter(|x| **x).count())
+            .sum()
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> Vec<bool> {
+        let bits = bits_from_bytes(bytes);
+
+        if *self == CodeRate::Uncoded {
+            return bits;
+        }
+
+        let pattern = self.puncture();
+        let mut state = 0;
+        let mut ret = Vec::with_capacity(self.coded_len(bytes.len()));
+
+        for (i, bit) in bits.into_iter().chain([false; K - 1]).enumerate() {
+            let (out, next) = Self::transition(state, bit);
+
+            ret.extend(
+                out.iter()
+                    .zip(pattern[i % pattern.len()])
+                    .filter(|(_, keep)| *keep)
+                    .map(|(x, _)| *x),
+            );
+
+            state = next;
+        }
+
+        ret
+    }
+
+    /// Decode `n_bytes` bytes from a block of soft bits.  Each soft bit is a
+    /// log-likelihood ratio: positive values favour a `0`, negative values a
+    /// `1` and the magnitude indicates confidence.
+    pub fn decode(&self, soft: &[f32], n_bytes: usize) -> Vec<u8> {
+        assert_eq!(soft.len(), self.coded_len(n_bytes));
+
+        let bits = if *self == CodeRate::Uncoded {
+            soft.iter().map(|x| *x < 0.0).collect()
+        } else {
+            Self::viterbi(&self.depuncture(soft))
+        };
+
+        bytes_from_bits(&bits[..n_bytes * 8])
+    }
+
+    fn parity(x: u8) -> bool {
+        x.count_ones() & 1 == 1
+    }
+
+    fn transition(state: usize, bit: bool) -> ([bool; 2], usize) {
+        let reg = ((bit as u8) << (K - 1)) | state as u8;
+
+        (
+            [Self::parity(reg & G0), Self::parity(reg & G1)],
+            reg as usize >> 1,
+        )
+    }
+
+    /// Re-insert erasures (zero confidence soft bits) in the positions that
+    /// were removed by puncturing.
+    fn depuncture(&self, soft: &[f32]) -> Vec<[f32; 2]> {
+        let pattern = self.puncture();
+        let mut soft = soft.iter();
+        let mut ret = Vec::new();
+
+        for keep in pattern.iter().cycle() {
+            if soft.len() == 0 {
+                break;
+            }
+
+            let mut pair = [0.0; 2];
+
+            for (x, keep) in pair.iter_mut().zip(keep) {
+                if *keep {
+                    *x = *soft.next().unwrap();
+                }
+            }
+
+            ret.push(pair);
+        }
+
+        ret
+    }
+
+    fn viterbi(soft: &[[f32; 2]]) -> Vec<bool> {
+        let mut metrics = [f32::NEG_INFINITY; N_STATES];
+        let mut decisions = Vec::with_capacity(soft.len());
+
+        metrics[0] = 0.0;
+
+        for pair in soft {
+            let mut next_metrics = [f32::NEG_INFINITY; N_STATES];
+            let mut decision = [0u8; N_STATES];
+
+            for (state, metric) in metrics.iter().enumerate() {
+                if *metric == f32::NEG_INFINITY {
+                    continue;
+                }
+
+                for bit in [false, true] {
+                    let (out, next) = Self::transition(state, bit);
+
+                    let branch: f32 = out
+                        .iter()
+                        .zip(pair)
+                        .map(|(b, s)| if *b { -s } else { *s })
+                        .sum();
+
+                    if metric + branch > next_metrics[next] {
+                        next_metrics[next] = metric + branch;
+                        decision[next] = state as u8 & 1;
+                    }
+                }
+            }
+
+            metrics = next_metrics;
+            decisions.push(decision);
+        }
+
+        // The trellis is terminated so the survivor path always ends in state
+        // zero.
+        let mut state = 0;
+        let mut bits: Vec<bool> = decisions
+            .iter()
+            .rev()
+            .map(|decision| {
+                let bit = state >> (K - 2) == 1;
+                state = ((state << 1) & (N_STATES - 1)) | decision[state] as usize;
+                bit
+            })
+            .collect();
+
+        bits.reverse();
+        bits.truncate(soft.len() - (K - 1));
+        bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CodeRate;
+
+    const PAYLOAD: [u8; 8] = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x00, 0xff, 0x55];
+
+    fn soft(bits: &[bool]) -> Vec<f32> {
+        bits.iter().map(|b| if *b { -1.0 } else { 1.0 }).collect()
+    }
+
+    fn run(rate: CodeRate, errors: &[usize]) -> Vec<u8> {
+        let coded = rate.encode(&PAYLOAD);
+        assert_eq!(coded.len(), rate.coded_len(PAYLOAD.len()));
+
+        let mut soft = soft(&coded);
+
+        // Each QPSK symbol carries two coded bits, so a symbol error flips a
+        // pair of adjacent bits.
+        for sym in errors {
+            soft[sym * 2] *= -1.0;
+            soft[sym * 2 + 1] *= -1.0;
+        }
+
+        rate.decode(&soft, PAYLOAD.len())
+    }
+
+    #[test]
+    fn coded_len() {
+        assert_eq!(CodeRate::Uncoded.coded_len(2), 16);
+        assert_eq!(CodeRate::Half.coded_len(2), 44);
+        assert_eq!(CodeRate::TwoThirds.coded_len(2), 33);
+        assert_eq!(CodeRate::ThreeQuarters.coded_len(2), 30);
+    }
+
+    #[test]
+    fn no_errors() {
+        for rate in [
+            CodeRate::Uncoded,
+            CodeRate::Half,
+            CodeRate::TwoThirds,
+            CodeRate::ThreeQuarters,
+        ] {
+            assert_eq!(run(rate, &[]), PAYLOAD);
+        }
+    }
+
+    #[test]
+    fn half_sym_errors() {
+        assert_eq!(run(CodeRate::Half, &[3, 20, 40, 60]), PAYLOAD);
+    }
+
+    #[test]
+    fn two_thirds_sym_errors() {
+        assert_eq!(run(CodeRate::TwoThirds, &[5, 25, 45]), PAYLOAD);
+    }
+
+    #[test]
+    fn three_quarters_sym_errors() {
+        assert_eq!(run(CodeRate::ThreeQuarters, &[5, 40]), PAYLOAD);
+    }
+
+    #[test]
+    fn uncoded_sym_error() {
+        assert_ne!(run(CodeRate::Uncoded, &[5]), PAYLOAD);
+    }
+
+    #[test]
+    fn erasures() {
+        let coded = CodeRate::Half.encode(&PAYLOAD);
+        let mut soft = soft(&coded);
+
+        soft[10..20].iter_mut().for_each(|x| *x = 0.0);
+
+        assert_eq!(CodeRate::Half.decode(&soft, PAYLOAD.len()), PAYLOAD);
+    }
+}
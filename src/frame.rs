@@ -1,24 +1,173 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, f32::consts::PI};
 
 use anyhow::Result;
 use futuresdr::{
     async_trait::async_trait,
     macros::message_handler,
+    num_complex::Complex32,
     runtime::{
         Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
         StreamIoBuilder, WorkIo,
     },
 };
 
-use crate::sym::{Sym, Symbol};
-use crate::sym_sync::{SymSync, SYNC};
+use crate::arq::FLAG_ARQ;
+use crate::constellation::{Constellation, Modulation};
+use crate::crc::{crc32, CRC_LEN};
+use crate::fec::CodeRate;
+use crate::header::{Frame, FrameHeader, FrameType, HEADER_LEN};
+use crate::interleave::Interleaver;
+use crate::ldpc;
+use crate::link_quality::{EvmMeter, Quality};
+use crate::reed_solomon;
+use crate::scrambler::Scrambler;
+use crate::sym::{SoftSym, Sym, Symbol, MAGNITUDE};
+use crate::sym_sync::{Detection, SymSync, SYNC};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PayloadCode {
+    /// The convolutional code, at the rate given by `Coding::rate`.
+    Convolutional,
+    /// Rate 1/2 IEEE 802.11n LDPC code with 648 bit codewords.
+    Ldpc,
+}
+
+/// Largest payload carried by a frame, in bytes.
+pub const MAX_FRAME_SZ: usize = 1500;
+
+/// The coding applied to each frame.  Both ends of the link must agree on the
+/// coding used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::Args)]
+pub struct Coding {
+    /// Convolutional code rate, applied to both the header and the data.
+    #[arg(long, value_enum, default_value = "1/2")]
+    pub rate: CodeRate,
+    /// The code used to protect the data.  The header is always protected by
+    /// the convolutional code.
+    #[arg(long, value_enum, default_value = "convolutional")]
+    pub payload: PayloadCode,
+    /// Protect the data with an RS(255, 223) outer code.
+    #[arg(long)]
+    pub reed_solomon: bool,
+    /// Interleaving of the coded data symbols.
+    #[command(flatten)]
+    pub interleaver: Interleaver,
+    /// Modulation of the data.  The sync words and header are always sent
+    /// with QPSK.
+    #[arg(long, value_enum, default_value = "qpsk")]
+    pub modulation: Modulation,
+    /// Number of data symbols between each group of pilot symbols, which let
+    /// the receiver correct phase slips partway through a frame.  Zero sends
+    /// no pilots.
+    #[arg(long, default_value_t = 0)]
+    pub pilot_interval: usize,
+}
+
+impl Default for Coding {
+    fn default() -> Self {
+        Self {
+            rate: CodeRate::Half,
+            payload: PayloadCode::Convolutional,
+            reed_solomon: false,
+            interleaver: Interleaver::default(),
+            modulation: Modulation::Qpsk,
+            pilot_interval: 0,
+        }
+    }
+}
+
+impl Coding {
+    /// Identifies the code and modulation of the data, sent in the header of
+    /// every frame so that a receiver set up differently drops the frame
+    /// rather than decoding it.  The interleaving and pilots aren't included.
+    pub fn id(&self) -> u8 {
+        self.rate as u8
+            | (self.payload as u8) << 2
+            | (self.reed_solomon as u8) << 3
+            | (self.modulation as u8) << 4
+    }
+
+    /// Number of bytes in the data block of a frame carrying `frame_sz` bytes
+    /// of payload.
+    fn data_len(&self, frame_sz: usize) -> usize {
+        if self.reed_solomon {
+            reed_solomon::encoded_len(frame_sz + CRC_LEN)
+        } else {
+            frame_sz + CRC_LEN
+        }
+    }
+
+    fn encode_data(&self, bytes: &[u8]) -> Vec<bool> {
+        match self.payload {
+            PayloadCode::Convolutional => self.rate.encode(bytes),
+            PayloadCode::Ldpc => ldpc::encode(bytes),
+        }
+    }
+
+    /// Number of coded bits in the data block for `n_bytes` bytes of data.
+    fn data_coded_len(&self, n_bytes: usize) -> usize {
+        match self.payload {
+            PayloadCode::Convolutional => self.rate.coded_len(n_bytes),
+            PayloadCode::Ldpc => ldpc::coded_len(n_bytes),
+        }
+    }
+
+    fn decode_data(&self, soft: &[f32], n_bytes: usize) -> Vec<u8> {
+        match self.payload {
+            PayloadCode::Convolutional => self.rate.decode(soft, n_bytes),
+            PayloadCode::Ldpc => ldpc::decode(soft, n_bytes),
+        }
+    }
+
+    /// Whether a group of pilots is sent after the first `n_syms` symbols of
+    /// the data block.
+    fn pilots_after(&self, n_syms: usize) -> bool {
+        self.pilot_interval > 0 && n_syms > 0 && n_syms.is_multiple_of(self.pilot_interval)
+    }
+}
+
+/// Split a block of coded bits into the groups carried by each symbol,
+/// padding it out to a whole number of symbols.
+fn bit_groups(mut bits: Vec<bool>, bits_per_sym: usize) -> Vec<Vec<bool>> {
+    bits.resize(bits.len().next_multiple_of(bits_per_sym), false);
+
+    bits.chunks_exact(bits_per_sym)
+        .map(<[bool]>::to_vec)
+        .collect()
+}
+
+/// Known QPSK symbols sent every `Coding::pilot_interval` data symbols, one in
+/// each quadrant so that their phase can be measured whatever the data
+/// around them.
+const PILOT: [Sym; 4] = [Sym::A, Sym::D, Sym::B, Sym::C];
+
+/// Rotation that turns a QPSK point into the change in phase it carries in
+/// DQPSK mode, a unit phasor with `00` leaving the phase unchanged.
+fn dqpsk_rotation() -> Complex32 {
+    Complex32::from_polar(1.0 / MAGNITUDE, -PI / 4.0)
+}
+
+/// Turns packets into frames of symbols.  Packets posted to `in` are sent as
+/// data frames, numbered by the encoder, while frames posted to `frame` are
+/// sent with the type, sequence number and flags they are given.
+///
+/// `U32(1)` is posted to the `busy` message output when the encoder starts
+/// sending symbols, and `U32(0)` once it has sent them all, or when a frame
+/// posted to `frame` is dropped while it's idle.
 pub struct FrameEncoder {
     sym_queue: VecDeque<Symbol>,
+    coding: Coding,
+    /// Sequence number of the next packet posted to `in`.
+    seq: u16,
+    /// Whether symbols of a frame are being sent.
+    sending: bool,
+    scrambler: Scrambler,
+    qpsk: Box<dyn Constellation>,
+    constellation: Box<dyn Constellation>,
 }
 
 impl FrameEncoder {
-    pub fn new() -> Block {
+    pub fn new(coding: Coding) -> Block {
         Block::new(
             BlockMetaBuilder::new("FrameEncoder").build(),
             StreamIoBuilder::new()
@@ -26,44 +175,113 @@ impl FrameEncoder {
                 .build(),
             MessageIoBuilder::new()
                 .add_input("in", Self::pkt_handler)
+                .add_input("frame", Self::frame_handler)
+                .add_output("busy")
                 .build(),
-            Self::create(),
+            Self::create(coding),
         )
     }
 
-    fn create() -> Self {
+    fn create(coding: Coding) -> Self {
         Self {
             sym_queue: VecDeque::new(),
+            coding,
+            seq: 0,
+            sending: false,
+            scrambler: Scrambler::new(),
+            qpsk: Modulation::Qpsk.constellation(),
+            constellation: coding.modulation.constellation(),
         }
     }
 
-    fn push_sync(&mut self) {
-        self.sym_queue.extend(SYNC.iter().map(|x| Some(*x)));
+    /// The symbols of a data frame carrying `payload`.
+    #[cfg(test)]
+    pub(crate) fn frame_syms(coding: Coding, payload: &[u8]) -> Vec<Complex32> {
+        let mut encoder = Self::create(coding);
+        encoder.push_frame(payload);
+
+        encoder.sym_queue.iter().map(|x| x.unwrap()).collect()
     }
 
-    fn push_sz(&mut self, len: u16) {
-        self.push_byte((len >> 8) as u8);
-        self.push_byte((len & 0xff) as u8);
+    fn push_sync(&mut self) {
+        self.sym_queue
+            .extend(SYNC.iter().map(|x| Some(Complex32::from(x))));
     }
 
-    fn push_byte(&mut self, byte: u8) {
-        Sym::syms_from_byte(byte)
-            .into_iter()
-            .for_each(|s| self.sym_queue.push_back(Some(s)));
+    /// Scramble and map the bit groups of a block, the header is sent with
+    /// QPSK and the data with the chosen modulation, interspersed with
+    /// pilots.  In DQPSK mode both are differentially encoded, starting from
+    /// the last sync symbol, while the pilots are not.
+    fn push_block(&mut self, groups: Vec<Vec<bool>>, data: bool) {
+        let constellation = if data {
+            &self.constellation
+        } else {
+            &self.qpsk
+        };
+
+        for (i, mut bits) in groups.into_iter().enumerate() {
+            if data && self.coding.pilots_after(i) {
+                self.sym_queue
+                    .extend(PILOT.iter().map(|x| Some(Complex32::from(x))));
+            }
+
+            self.scrambler.scramble(&mut bits);
+
+            let mut p = constellation.map(&bits);
+
+            if self.coding.modulation.is_differential() {
+                let prev = self.sym_queue.back().copied().flatten().unwrap();
+                p = self.qpsk.decide(prev * p * dqpsk_rotation());
+            }
+
+            self.sym_queue.push_back(Some(p));
+        }
     }
 
-    fn push_bytes(&mut self, bytes: &Vec<u8>) {
-        bytes
-            .iter()
-            .flat_map(|byte| Sym::syms_from_byte(*byte))
-            .for_each(|sym| self.sym_queue.push_back(Some(sym)))
+    fn push_frame(&mut self, bytes: &[u8]) {
+        let frame = Frame {
+            frame_type: FrameType::Data,
+            seq: self.seq,
+            flags: 0,
+            src: 0,
+            payload: bytes.to_vec(),
+            quality: None,
+        };
+        self.seq = self.seq.wrapping_add(1);
+
+        self.encode(&frame);
     }
 
-    fn push_frame(&mut self, bytes: &Vec<u8>) {
+    fn encode(&mut self, frame: &Frame) {
+        let bytes = &frame.payload;
+        let mut data = bytes.to_vec();
+        data.extend(crc32(bytes).to_be_bytes());
+
+        if self.coding.reed_solomon {
+            data = reed_solomon::encode(&data);
+        }
+
+        let header = FrameHeader {
+            frame_type: frame.frame_type,
+            seq: frame.seq,
+            flags: frame.flags,
+            coding_id: self.coding.id(),
+            len: bytes.len() as u16,
+            src: frame.src,
+        };
+
+        let k = self.constellation.bits_per_symbol();
+        let header = bit_groups(self.coding.rate.encode(&header.to_bytes()), 2);
+        let data = self.coding.interleaver.interleave(
+            &bit_groups(self.coding.encode_data(&data), k),
+            vec![false; k],
+        );
+
         self.push_sync();
         self.push_sync();
-        self.push_sz(bytes.len() as u16);
-        self.push_bytes(bytes);
+        self.scrambler.reset();
+        self.push_block(header, false);
+        self.push_block(data, true);
     }
 
     #[message_handler]
@@ -74,7 +292,37 @@ impl FrameEncoder {
         p: Pmt,
     ) -> Result<Pmt> {
         if let Pmt::Blob(ref data) = p {
-            self.push_frame(data);
+            if data.len() > MAX_FRAME_SZ {
+                eprintln!("Dropping {} byte packet, too large to send", data.len());
+            } else {
+                self.push_frame(data);
+            }
+        }
+
+        Ok(Pmt::Null)
+    }
+
+    #[message_handler]
+    async fn frame_handler(
+        &mut self,
+        mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Some(frame) = Frame::from_pmt(&p) {
+            if frame.payload.len() > MAX_FRAME_SZ {
+                eprintln!(
+                    "Dropping {} byte frame, too large to send",
+                    frame.payload.len()
+                );
+
+                // Whoever sent the frame may be waiting for it to finish.
+                if !self.sending {
+                    mio.post(0, Pmt::U32(0)).await;
+                }
+            } else {
+                self.encode(&frame);
+            }
         }
 
         Ok(Pmt::Null)
@@ -87,18 +335,28 @@ impl Kernel for FrameEncoder {
         &mut self,
         _io: &mut WorkIo,
         sio: &mut StreamIo,
-        _mio: &mut MessageIo<Self>,
+        mio: &mut MessageIo<Self>,
         _b: &mut BlockMeta,
     ) -> Result<()> {
         let output = sio.output(0);
         let o: &mut [Symbol] = output.slice();
 
         if self.sym_queue.is_empty() {
+            if self.sending {
+                self.sending = false;
+                mio.post(0, Pmt::U32(0)).await;
+            }
+
             o.iter_mut().for_each(|x| *x = None);
             output.produce(o.len());
             return Ok(());
         }
 
+        if !self.sending {
+            self.sending = true;
+            mio.post(0, Pmt::U32(1)).await;
+        }
+
         let mut iter = o.iter_mut().enumerate();
 
         for (_, o) in &mut iter {
@@ -119,155 +377,583 @@ impl Kernel for FrameEncoder {
     }
 }
 
-struct ByteDecoder {
-    cur_byte: u8,
-    bits_pushed: u8,
+/// Number of symbols used to carry a block of `n_bits` coded bits.
+fn block_syms(n_bits: usize, bits_per_sym: usize) -> usize {
+    n_bits.div_ceil(bits_per_sym)
+}
+
+/// Number of symbols carrying the header of a frame.
+fn header_syms(coding: &Coding) -> usize {
+    block_syms(coding.rate.coded_len(HEADER_LEN), 2)
 }
 
-impl ByteDecoder {
-    fn new() -> Self {
+enum DemapperState {
+    Sync,
+    /// The first `n` symbols of the header have been received.
+    Header {
+        n: usize,
+    },
+    /// The header has been received, and is being decoded.
+    Wait,
+    /// The first `n` of the `len` data symbols have been received, not
+    /// counting the pilots.
+    Data {
+        n: usize,
+        len: usize,
+    },
+}
+
+/// Finds the frames in the symbols of the demodulator and demaps their
+/// symbols to soft bits, the header with QPSK and the data with the
+/// modulation of the coding.
+///
+/// The search for the sync word carries on while the header is received, so
+/// that the second sync word restarts the frame, but stops once the data
+/// starts: a payload that happens to contain the sync word can't cut its own
+/// frame short.  Only the frame decoder knows how many data symbols follow
+/// the header, so once the header has been received nothing more is demapped
+/// until it is given the number through `start_data`.
+///
+/// With 8PSK decisions the carrier sync can lock an eighth of a turn away
+/// from the QPSK points of the sync words, so for 8PSK the sync word is also
+/// looked for an eighth of a turn round.
+///
+/// The rotation found from the sync word is checked against each group of
+/// pilots in the data, and corrected should the carrier sync have slipped by
+/// a quarter turn, or an eighth of a turn for 8PSK, since.
+pub(crate) struct FrameDemapper {
+    coding: Coding,
+    sym_sync: SymSync,
+    /// Searches for the sync word an eighth of a turn round, for 8PSK only.
+    eighth_sync: Option<SymSync>,
+    state: DemapperState,
+    /// Whether the constellation was found to be mirrored, which is undone
+    /// before the rotation.
+    mirrored: bool,
+    /// Unit phasor that undoes the rotation of the constellation.
+    rotation: Complex32,
+    /// Number of symbols of the current group of pilots received so far.
+    pilot_pos: usize,
+    /// Sum of the derotated pilots so far, each multiplied by the conjugate
+    /// of the symbol sent.
+    pilot_err: Complex32,
+    /// The previous symbol, the reference for DQPSK.
+    prev: Complex32,
+    qpsk: Box<dyn Constellation>,
+    constellation: Box<dyn Constellation>,
+    /// Decision constellation used to measure the quality of each frame.
+    decision: Box<dyn Constellation>,
+    meter: EvmMeter,
+    phase_slips: usize,
+}
+
+impl FrameDemapper {
+    /// `sync_threshold` is the largest number of bit errors allowed in the
+    /// sync word.
+    pub(crate) fn new(coding: Coding, sync_threshold: u32) -> Self {
         Self {
-            cur_byte: 0,
-            bits_pushed: 0,
+            coding,
+            sym_sync: SymSync::new(sync_threshold),
+            eighth_sync: (coding.modulation.symmetry() == 8).then(|| SymSync::new(sync_threshold)),
+            state: DemapperState::Sync,
+            mirrored: false,
+            rotation: Complex32::new(1.0, 0.0),
+            pilot_pos: 0,
+            pilot_err: Complex32::new(0.0, 0.0),
+            prev: Complex32::new(0.0, 0.0),
+            qpsk: Modulation::Qpsk.constellation(),
+            constellation: coding.modulation.constellation(),
+            decision: coding.modulation.decision_constellation(),
+            meter: EvmMeter::default(),
+            phase_slips: 0,
         }
     }
 
-    fn push_sym(&mut self, s: Sym) -> Option<u8> {
-        self.cur_byte |= <Sym as Into<u8>>::into(s);
-        self.bits_pushed += 2;
+    /// Whether the demapper is waiting to be told the length of the data.
+    pub(crate) fn waiting(&self) -> bool {
+        matches!(self.state, DemapperState::Wait)
+    }
 
-        if self.bits_pushed == 8 {
-            let ret = self.cur_byte;
-            self.reset();
-            Some(ret)
+    /// Start demapping the `len` data symbols that follow the header just
+    /// received, or go back to hunting for the sync word if `len` is zero.
+    pub(crate) fn start_data(&mut self, len: usize) {
+        if !self.waiting() {
+            return;
+        }
+
+        self.state = if len == 0 {
+            DemapperState::Sync
+        } else {
+            DemapperState::Data { n: 0, len }
+        };
+    }
+
+    /// Push the next symbol into the search for the sync word, returning the
+    /// sync word if it was found along with the phasor that undoes the
+    /// rotation of the constellation.
+    fn find_sync(&mut self, x: Complex32) -> Option<(Detection, Complex32)> {
+        let quarters = |d: &Detection| Complex32::i().powi(d.rotation as i32);
+        let eighth = Complex32::from_polar(1.0, PI / 4.0);
+
+        let straight = self.sym_sync.push_sym(Sym::from_point(x));
+        let turned = self
+            .eighth_sync
+            .as_mut()
+            .and_then(|s| s.push_sym(Sym::from_point(x * eighth)));
+
+        // Both searches only find the sync word when its symbols sit on the
+        // boundaries between the quadrants of one of them, so it belongs to
+        // the other: the straight one if the symbols are on the diagonals.
+        let straight = straight.filter(|_| turned.is_none() || x.powi(4).re < 0.0);
+
+        straight.map(|d| (d, quarters(&d))).or(turned.map(|d| {
+            // The eighth turn was applied before any mirroring is undone.
+            let eighth = if d.mirrored { eighth.conj() } else { eighth };
+
+            (d, quarters(&d) * eighth)
+        }))
+    }
+
+    /// Undo any mirroring of the constellation found by the sync.
+    fn unmirror(&self, x: Complex32) -> Complex32 {
+        if self.mirrored {
+            x.conj()
         } else {
-            self.cur_byte <<= 2;
-            None
+            x
         }
     }
 
-    fn reset(&mut self) {
-        self.bits_pushed = 0;
-        self.cur_byte = 0;
+    /// Undo the mirroring and rotation of the constellation.
+    fn derotate(&self, x: Complex32) -> Complex32 {
+        self.unmirror(x) * self.rotation
     }
-}
 
-struct U16Decoder {
-    bd: ByteDecoder,
-    high_byte: Option<u8>,
-}
+    /// Push the next symbol of a group of pilots.  Once the whole group has
+    /// been received the rotation is corrected by the nearest multiple of the
+    /// angle the modulation is symmetric under to the phase of the pilots:
+    /// a quarter turn, or an eighth of a turn for 8PSK.
+    fn push_pilot(&mut self, x: Complex32) {
+        self.pilot_err += self.derotate(x) * Complex32::from(&PILOT[self.pilot_pos]).conj();
+        self.pilot_pos += 1;
 
-impl U16Decoder {
-    fn new() -> Self {
-        Self {
-            bd: ByteDecoder::new(),
-            high_byte: None,
+        if self.pilot_pos < PILOT.len() {
+            return;
+        }
+
+        let err = std::mem::replace(&mut self.pilot_err, Complex32::new(0.0, 0.0));
+        let step = 2.0 * PI / self.coding.modulation.symmetry() as f32;
+        let slip = (err.arg() / step).round() * step;
+
+        // DQPSK doesn't depend on the rotation, so the pilots are only
+        // skipped over.
+        if slip == 0.0 || self.coding.modulation.is_differential() {
+            return;
         }
+
+        self.rotation = Complex32::from_polar(1.0, self.rotation.arg() - slip);
+        self.phase_slips += 1;
+        eprintln!(
+            "Corrected phase slip of {:.0} degrees ({} corrected so far)",
+            slip.to_degrees(),
+            self.phase_slips
+        );
     }
 
-    fn push_sym(&mut self, s: Sym) -> Option<u16> {
-        if let Some(byte) = self.bd.push_sym(s) {
-            if let Some(high_byte) = self.high_byte {
-                self.high_byte = None;
-                Some((high_byte as u16) << 8 | byte as u16)
-            } else {
-                self.high_byte = Some(byte);
-                None
+    /// Push the next symbol, scaled to the constellation, along with the
+    /// estimated noise variance of each of its components.  Any soft output
+    /// is added to `out`.
+    pub(crate) fn push_sym(&mut self, x: Complex32, noise_var: f32, out: &mut VecDeque<SoftSym>) {
+        let prev = std::mem::replace(&mut self.prev, x);
+
+        let hunting = matches!(
+            self.state,
+            DemapperState::Sync | DemapperState::Header { .. }
+        );
+        let sync = hunting.then(|| self.find_sync(x)).flatten();
+
+        if let Some((sync, rotation)) = sync {
+            if sync.distance > 0 {
+                eprintln!(
+                    "Found sync word with {} bit errors (confidence {:.6})",
+                    sync.distance,
+                    sync.confidence()
+                );
             }
-        } else {
-            None
+
+            self.mirrored = sync.mirrored;
+            self.rotation = rotation;
+            self.pilot_pos = 0;
+            self.pilot_err = Complex32::new(0.0, 0.0);
+            self.meter.reset();
+            self.state = DemapperState::Header { n: 0 };
+            out.push_back(SoftSym::Sync);
+            return;
         }
-    }
 
-    fn reset(&mut self) {
-        self.high_byte = None;
-        self.bd.reset();
+        let constellation = match self.state {
+            DemapperState::Sync | DemapperState::Wait => return,
+            DemapperState::Header { .. } => &self.qpsk,
+            DemapperState::Data { n, .. } => {
+                if self.coding.pilots_after(n) && self.pilot_pos < PILOT.len() {
+                    self.push_pilot(x);
+                    return;
+                }
+
+                self.pilot_pos = 0;
+                &self.constellation
+            }
+        };
+
+        self.meter.push(x, self.decision.decide(x));
+
+        let (x, noise_var) = if self.coding.modulation.is_differential() {
+            // The change in phase from the previous symbol, which doesn't
+            // depend on the rotation of the constellation.  Both symbols
+            // contribute noise.
+            (
+                self.unmirror(x) * self.unmirror(prev).conj()
+                    / dqpsk_rotation()
+                    / MAGNITUDE.powi(2),
+                2.0 * noise_var,
+            )
+        } else {
+            (self.derotate(x), noise_var)
+        };
+
+        out.push_back(SoftSym::from_llrs(&constellation.demap(x, noise_var)));
+
+        self.state = match self.state {
+            DemapperState::Header { n } if n + 1 == header_syms(&self.coding) => {
+                DemapperState::Wait
+            }
+            DemapperState::Header { n } => DemapperState::Header { n: n + 1 },
+            DemapperState::Data { n, len } if n + 1 == len => {
+                out.push_back(SoftSym::End(self.meter.quality().unwrap()));
+                DemapperState::Sync
+            }
+            DemapperState::Data { n, len } => DemapperState::Data { n: n + 1, len },
+            DemapperState::Sync | DemapperState::Wait => unreachable!(),
+        };
     }
 }
 
+/// Number of failed frames whose soft bits are kept for combining with their
+/// retransmissions.  The oldest is dropped to make room for another.
+const HARQ_FRAMES: usize = 16;
+
+/// The soft bits of a frame that failed its CRC, kept until it is
+/// retransmitted.
+struct HarqBuffer {
+    header: FrameHeader,
+    /// Sum of the soft bits of the coded data of every reception so far.
+    soft: Vec<f32>,
+    receptions: usize,
+}
+
 enum DecoderState {
     Sync,
-    Sz,
+    Header,
     Data,
 }
 
+/// Recovers frames from the soft output of the demodulator, posting the
+/// payload of each data frame to the `out` message output and every frame,
+/// with its header fields and the EVM and SNR of its symbols, to `frame`.
+/// `U32(1)` is posted to `busy` when a sync word is found, and `U32(0)` when
+/// the decoder goes back to hunting for the next one.
+///
+/// Once the header of a frame has been decoded the number of data symbols
+/// that follow it is posted to `data`, as a `U32`, which the demodulator
+/// waits for before demapping them.  Frames whose header fails its CRC, or is
+/// for a different version, coding or a payload larger than `MAX_FRAME_SZ`,
+/// are dropped straight away by posting zero, so a corrupt header can't hold
+/// up the search for the next sync word for long.
+///
+/// The soft bits of ARQ frames that fail their CRC are kept, and when a frame
+/// with an identical header, from the same station, fails in turn its soft
+/// bits are added to them and decoded again: Chase combining of the
+/// retransmissions sent by ARQ, which recovers a frame that no single
+/// reception could.  Other frames are never retransmitted, so aren't kept.
 pub struct FrameDecoder {
-    sym_sync: SymSync,
     state: DecoderState,
+    coding: Coding,
     frame_sz: u16,
-    rotation: usize,
-    frame_sz_decoder: U16Decoder,
-    data: Vec<u8>,
-    data_decoder: ByteDecoder,
+    /// Header of the frame being received, once it has been decoded.
+    header: Option<FrameHeader>,
+    /// Number of data symbols of the frame, waiting to be posted to `data`.
+    data_syms: Option<usize>,
+    scrambler: Scrambler,
+    constellation: Box<dyn Constellation>,
+    /// Quality of the symbols of the last frame received.
+    quality: Option<Quality>,
+    soft_bits: Vec<f32>,
+    block_len: usize,
+    /// Frames that failed their CRC, oldest first.
+    harq: VecDeque<HarqBuffer>,
+    crc_errors: usize,
+    harq_recoveries: usize,
+    header_errors: usize,
+    rs_corrections: usize,
 }
 
 impl FrameDecoder {
-    pub fn new() -> Block {
+    pub fn new(coding: Coding) -> Block {
         Block::new(
             BlockMetaBuilder::new("FrameDecoder").build(),
             StreamIoBuilder::new()
-                .add_input("in", std::mem::size_of::<Symbol>())
+                .add_input("in", std::mem::size_of::<SoftSym>())
                 .build(),
-            MessageIoBuilder::new().add_output("out").build(),
-            Self::create(),
+            MessageIoBuilder::new()
+                .add_output("out")
+                .add_output("frame")
+                .add_output("busy")
+                .add_output("data")
+                .build(),
+            Self::create(coding),
         )
     }
 
-    fn create() -> Self {
+    fn create(coding: Coding) -> Self {
         Self {
-            sym_sync: SymSync::new(),
             state: DecoderState::Sync,
-            rotation: 0,
+            coding,
             frame_sz: 0,
-            frame_sz_decoder: U16Decoder::new(),
-            data: Vec::new(),
-            data_decoder: ByteDecoder::new(),
+            header: None,
+            data_syms: None,
+            scrambler: Scrambler::new(),
+            constellation: coding.modulation.constellation(),
+            quality: None,
+            soft_bits: Vec::new(),
+            block_len: 0,
+            harq: VecDeque::new(),
+            crc_errors: 0,
+            harq_recoveries: 0,
+            header_errors: 0,
+            rs_corrections: 0,
         }
     }
 
-    fn push_sym(&mut self, s: Sym) -> Option<Vec<u8>> {
-        if let Some(rotation) = self.sym_sync.push_sym(s) {
-            self.rotation = rotation;
-            self.reset();
-            self.state = DecoderState::Sz;
+    /// Decode the soft bits of the coded data, returning the frame if it
+    /// passes its CRC.
+    fn decode_frame(&mut self, soft: &[f32]) -> Option<Frame> {
+        let n_bytes = self.coding.data_len(self.frame_sz as usize);
+        let mut data = self.coding.decode_data(soft, n_bytes);
+
+        if self.coding.reed_solomon {
+            let corrections;
+            (data, corrections) = reed_solomon::decode(&data);
+
+            if corrections > 0 {
+                self.rs_corrections += corrections;
+                eprintln!(
+                    "Corrected {} bytes in frame ({} corrected so far)",
+                    corrections, self.rs_corrections
+                );
+            }
+        }
+
+        let (payload, crc) = data.split_at(self.frame_sz as usize);
+
+        if crc != crc32(payload).to_be_bytes() {
             return None;
         }
 
-        let s = s.sub(self.rotation);
+        let header = self.header.unwrap();
 
-        match self.state {
-            DecoderState::Sync => None,
-            DecoderState::Sz => {
-                if let Some(sz) = self.frame_sz_decoder.push_sym(s) {
-                    self.frame_sz = sz;
-                    self.state = DecoderState::Data;
-                }
+        Some(Frame {
+            frame_type: header.frame_type,
+            seq: header.seq,
+            flags: header.flags,
+            src: header.src,
+            payload: payload.to_vec(),
+            quality: self.quality,
+        })
+    }
 
-                None
+    /// Combine the soft bits of a frame that failed its CRC with those of
+    /// earlier receptions of the same frame, if there were any, and decode
+    /// the frame again.  If it still fails the soft bits are kept for the
+    /// next retransmission.
+    fn combine(&mut self, mut soft: Vec<f32>) -> Option<Frame> {
+        let header = self.header.unwrap();
+        let mut receptions = 1;
+
+        if let Some(i) = self.harq.iter().position(|b| b.header == header) {
+            let buffer = self.harq.remove(i).unwrap();
+
+            for (s, b) in soft.iter_mut().zip(buffer.soft) {
+                *s += b;
             }
+            receptions += buffer.receptions;
 
-            DecoderState::Data => {
-                if let Some(byte) = self.data_decoder.push_sym(s) {
-                    self.data.push(byte);
+            if let Some(frame) = self.decode_frame(&soft) {
+                self.harq_recoveries += 1;
+                eprintln!(
+                    "Recovered frame by combining {} receptions ({} recovered so far)",
+                    receptions, self.harq_recoveries
+                );
+                return Some(frame);
+            }
+        }
 
-                    if self.data.len() == self.frame_sz as usize {
-                        let data = self.data.clone();
-                        self.reset();
-                        return Some(data);
-                    }
+        if self.harq.len() == HARQ_FRAMES {
+            self.harq.pop_front();
+        }
+
+        self.harq.push_back(HarqBuffer {
+            header,
+            soft,
+            receptions,
+        });
+
+        None
+    }
+
+    /// Parse a received header, checking that it is for a frame this decoder
+    /// can receive.
+    fn check_header(&mut self, bytes: [u8; HEADER_LEN]) -> Option<FrameHeader> {
+        let header = FrameHeader::from_bytes(&bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|h| {
+                if h.coding_id != self.coding.id() {
+                    Err(format!(
+                        "coding ID {:#04x}, expected {:#04x}",
+                        h.coding_id,
+                        self.coding.id()
+                    ))
+                } else if h.len as usize > MAX_FRAME_SZ {
+                    Err(format!("invalid size {}", h.len))
+                } else {
+                    Ok(h)
                 }
+            });
 
+        match header {
+            Ok(header) => Some(header),
+            Err(reason) => {
+                self.header_errors += 1;
+                eprintln!(
+                    "Dropping frame with {} ({} dropped so far)",
+                    reason, self.header_errors
+                );
                 None
             }
         }
     }
 
+    /// Push the next item of the soft output of the demodulator.
+    fn push_sym(&mut self, sym: SoftSym) -> Option<Frame> {
+        let mut soft = match (sym, &self.state) {
+            (SoftSym::Sync, _) => {
+                self.reset();
+                self.state = DecoderState::Header;
+                self.block_len = header_syms(&self.coding) * 2;
+                return None;
+            }
+            (SoftSym::End(quality), DecoderState::Data) => {
+                self.quality = Some(quality);
+                return self.end_frame();
+            }
+            (SoftSym::Bits { llr, len }, DecoderState::Header | DecoderState::Data) => {
+                llr[..len].to_vec()
+            }
+            _ => return None,
+        };
+
+        self.scrambler.descramble(&mut soft);
+        self.soft_bits.extend(soft);
+
+        if !matches!(self.state, DecoderState::Header) || self.soft_bits.len() < self.block_len {
+            return None;
+        }
+
+        let rate = self.coding.rate;
+        let bytes = rate.decode(&self.soft_bits[..rate.coded_len(HEADER_LEN)], HEADER_LEN);
+
+        let Some(header) = self.check_header(bytes.try_into().unwrap()) else {
+            self.reset();
+            self.data_syms = Some(0);
+            return None;
+        };
+
+        self.frame_sz = header.len;
+        self.header = Some(header);
+
+        let n_bytes = self.coding.data_len(self.frame_sz as usize);
+        let k = self.constellation.bits_per_symbol();
+        let n_syms = self
+            .coding
+            .interleaver
+            .interleaved_len(block_syms(self.coding.data_coded_len(n_bytes), k));
+
+        self.block_len = n_syms * k;
+        self.data_syms = Some(n_syms);
+        self.soft_bits.clear();
+        self.state = DecoderState::Data;
+
+        None
+    }
+
+    /// Decode the data of the frame once all of its symbols have been
+    /// received.
+    fn end_frame(&mut self) -> Option<Frame> {
+        if self.soft_bits.len() < self.block_len {
+            self.reset();
+            return None;
+        }
+
+        let n_bytes = self.coding.data_len(self.frame_sz as usize);
+        let n_bits = self.coding.data_coded_len(n_bytes);
+        let k = self.constellation.bits_per_symbol();
+
+        let syms: Vec<Vec<f32>> = self
+            .soft_bits
+            .chunks_exact(k)
+            .map(<[f32]>::to_vec)
+            .collect();
+        let mut soft: Vec<f32> = self
+            .coding
+            .interleaver
+            .deinterleave(&syms, block_syms(n_bits, k))
+            .into_iter()
+            .flatten()
+            .collect();
+        soft.truncate(n_bits);
+
+        let header = self.header.unwrap();
+        let frame = match self.decode_frame(&soft) {
+            Some(frame) => {
+                self.harq.retain(|b| b.header != header);
+                Some(frame)
+            }
+            None if header.flags & FLAG_ARQ != 0 => self.combine(soft),
+            None => None,
+        };
+
+        if frame.is_none() {
+            self.crc_errors += 1;
+            eprintln!(
+                "Dropping frame with bad CRC ({} dropped so far)",
+                self.crc_errors
+            );
+        }
+
+        self.reset();
+        frame
+    }
+
+    /// Whether a frame is being received, from its sync word onwards.
+    fn receiving(&self) -> bool {
+        !matches!(self.state, DecoderState::Sync)
+    }
+
     fn reset(&mut self) {
         self.state = DecoderState::Sync;
-        self.data.clear();
+        self.scrambler.reset();
+        self.soft_bits.clear();
+        self.block_len = 0;
         self.frame_sz = 0;
-        self.frame_sz_decoder.reset();
-        self.data_decoder.reset();
+        self.header = None;
     }
 }
 
@@ -280,20 +966,30 @@ impl Kernel for FrameDecoder {
         mio: &mut MessageIo<Self>,
         _b: &mut BlockMeta,
     ) -> Result<()> {
-        let input = sio.input(0).slice::<Symbol>();
+        let input = sio.input(0).slice::<SoftSym>();
+
+        for sym in input.iter() {
+            let receiving = self.receiving();
+
+            if let Some(frame) = self.push_sym(*sym) {
+                if frame.frame_type == FrameType::Data {
+                    mio.post(0, Pmt::Blob(frame.payload.clone())).await;
+                }
+                mio.post(1, frame.to_pmt()).await;
+            }
 
-        for samp in input.iter() {
-            if samp.is_none() {
-                continue;
+            if let Some(n) = self.data_syms.take() {
+                mio.post(3, Pmt::U32(n as u32)).await;
             }
 
-            if let Some(frame) = self.push_sym(samp.unwrap()) {
-                mio.post(0, Pmt::Blob(frame)).await;
+            if self.receiving() != receiving {
+                mio.post(2, Pmt::U32(self.receiving() as u32)).await;
             }
         }
 
         if sio.input(0).finished() {
             mio.post(0, Pmt::Null).await;
+            mio.post(1, Pmt::Null).await;
             io.finished = true;
         }
 
@@ -305,27 +1001,128 @@ impl Kernel for FrameDecoder {
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::VecDeque, f32::consts::PI};
+
     use anyhow::Result;
+    use futuresdr::{
+        blocks::{MessagePipe, NullSink, VectorSource},
+        futures::{channel::mpsc, StreamExt},
+        macros::connect,
+        num_complex::Complex32,
+        runtime::{Flowgraph, Pmt, Runtime},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+    use rand_distr::{Distribution, Normal};
+
+    use crate::{
+        agc::{Agc, AgcParams},
+        arq::FLAG_ARQ,
+        carrier_sync::CarrierSync,
+        channel::{Channel, ChannelParams},
+        clock_sync::ClockSync,
+        constellation::Modulation,
+        crc::CRC_LEN,
+        equaliser::{Equaliser, EqualiserParams},
+        fec::CodeRate,
+        freq_sync::FreqSync,
+        header::{Frame, FrameType, HEADER_LEN},
+        interleave::{Interleaver, InterleaverKind},
+        pulse_shape::PulseShape,
+        qam::{QamDemod, QamMod},
+        sym::{Sym, Symbol, MAGNITUDE},
+        sym_sync::{DEFAULT_THRESHOLD, SYNC},
+    };
+
+    use super::{
+        block_syms, Coding, DecoderState, FrameDecoder, FrameDemapper, FrameEncoder, PayloadCode,
+        MAX_FRAME_SZ, PILOT,
+    };
+
+    const UNCODED: Coding = Coding {
+        rate: CodeRate::Uncoded,
+        payload: PayloadCode::Convolutional,
+        reed_solomon: false,
+        interleaver: Interleaver {
+            kind: InterleaverKind::None,
+            depth: 16,
+            delay: 1,
+        },
+        modulation: Modulation::Qpsk,
+        pilot_interval: 0,
+    };
+
+    const MODULATIONS: [Modulation; 6] = [
+        Modulation::Bpsk,
+        Modulation::Qpsk,
+        Modulation::Dqpsk,
+        Modulation::Psk8,
+        Modulation::Qam16,
+        Modulation::Qam64,
+    ];
+
+    /// Noise variance reported alongside every symbol.
+    const NOISE_VAR: f32 = 0.01;
 
-    use crate::sym::Sym;
+    /// The demapper of the demodulator and the frame decoder, passing the
+    /// soft output and the length of the data between them as the blocks do.
+    struct Receiver {
+        demapper: FrameDemapper,
+        decoder: FrameDecoder,
+    }
+
+    impl Receiver {
+        fn new(coding: Coding) -> Self {
+            Receiver {
+                demapper: FrameDemapper::new(coding, DEFAULT_THRESHOLD),
+                decoder: FrameDecoder::create(coding),
+            }
+        }
+
+        /// Push the next symbol, returning the frame if it is the last one.
+        fn push_sym(&mut self, x: Complex32) -> Option<Frame> {
+            let mut soft = VecDeque::new();
+            self.demapper.push_sym(x, NOISE_VAR, &mut soft);
+
+            let mut frame = None;
+
+            for sym in soft {
+                frame = frame.or(self.decoder.push_sym(sym));
+
+                if let Some(n) = self.decoder.data_syms.take() {
+                    self.demapper.start_data(n);
+                }
+            }
+
+            frame
+        }
+    }
 
-    use super::{FrameDecoder, FrameEncoder};
+    /// Rotate a point by `n` quarter turns clockwise.
+    fn rotate(x: Complex32, n: usize) -> Complex32 {
+        (0..n).fold(x, |x, _| x * -Complex32::i())
+    }
 
-    fn run(mut sym_transform: impl FnMut(Sym) -> Sym) -> Result<()> {
-        let mut encoder = FrameEncoder::create();
-        let mut decoder = FrameDecoder::create();
+    fn run(coding: Coding, sym_transform: impl FnMut(usize, Complex32) -> Complex32) -> Result<()> {
+        run_payload(coding, vec![0xde, 0xad, 0xbe, 0xef], sym_transform)
+    }
 
-        let payload = vec![0xde, 0xad, 0xbe, 0xef];
+    fn run_payload(
+        coding: Coding,
+        payload: Vec<u8>,
+        mut sym_transform: impl FnMut(usize, Complex32) -> Complex32,
+    ) -> Result<()> {
+        let mut encoder = FrameEncoder::create(coding);
+        let mut rx = Receiver::new(coding);
 
         encoder.push_frame(&payload);
 
-        let mut it = encoder.sym_queue.iter().peekable();
+        let mut it = encoder.sym_queue.iter().enumerate().peekable();
 
-        while let Some(sym) = it.next() {
-            let v = decoder.push_sym(sym_transform(sym.unwrap()));
+        while let Some((i, sym)) = it.next() {
+            let v = rx.push_sym(sym_transform(i, sym.unwrap()));
 
             if it.peek().is_none() {
-                assert!(matches!(v, Some(_payload)));
+                assert_eq!(v.map(|f| f.payload), Some(payload.clone()));
             } else {
                 assert!(v.is_none())
             }
@@ -336,21 +1133,704 @@ mod tests {
 
     #[test]
     fn encode_decode() -> Result<()> {
-        run(|s| s)
+        run(UNCODED, |_, s| s)
     }
 
     #[test]
     fn encode_decode_rot_1() -> Result<()> {
-        run(|s| s.add(1))
+        run(UNCODED, |_, s| rotate(s, 1))
     }
 
     #[test]
     fn encode_decode_rot_2() -> Result<()> {
-        run(|s| s.add(2))
+        run(UNCODED, |_, s| rotate(s, 2))
     }
 
     #[test]
     fn encode_decode_rot_3() -> Result<()> {
-        run(|s| s.add(3))
+        run(UNCODED, |_, s| rotate(s, 3))
+    }
+
+    #[test]
+    fn encode_decode_coded() -> Result<()> {
+        for rate in [CodeRate::Half, CodeRate::TwoThirds, CodeRate::ThreeQuarters] {
+            run(Coding { rate, ..UNCODED }, |_, s| rotate(s, 1))?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn encode_decode_sym_errors() -> Result<()> {
+        // Symbol errors in both the header and data blocks (the sync words
+        // take up the first 32 symbols and the header the next 102).
+        run(
+            Coding {
+                rate: CodeRate::Half,
+                ..UNCODED
+            },
+            |i, s| match i {
+                40 | 90 | 150 => -s,
+                _ => s,
+            },
+        )
+    }
+
+    #[test]
+    fn sync_sym_errors() -> Result<()> {
+        // A quarter turn of a symbol flips one of its bits, two of which can
+        // be put up with in the second sync word.
+        run(UNCODED, |i, s| match i {
+            18 | 27 => rotate(s, 1),
+            _ => s,
+        })
+    }
+
+    #[test]
+    fn soft_decisions() -> Result<()> {
+        let coding = Coding {
+            rate: CodeRate::ThreeQuarters,
+            ..UNCODED
+        };
+
+        // Far more symbol errors than the code can correct from hard
+        // decisions, but all of them with little confidence.
+        run(coding, |i, s| match i {
+            40.. if i % 4 == 0 => -s * 0.1,
+            _ => s,
+        })
+    }
+
+    #[test]
+    fn modulations() -> Result<()> {
+        for modulation in MODULATIONS {
+            for n in 0..4 {
+                run(
+                    Coding {
+                        modulation,
+                        ..UNCODED
+                    },
+                    |_, s| rotate(s, n),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn psk8_eighth_turn() -> Result<()> {
+        // The carrier sync can lock to 8PSK an eighth of a turn away from the
+        // sync words, mirrored or not.
+        let coding = Coding {
+            modulation: Modulation::Psk8,
+            ..UNCODED
+        };
+
+        for n in [1, 3, 5, 7] {
+            let turn = Complex32::from_polar(1.0, n as f32 * PI / 4.0);
+
+            run(coding, |_, s| s * turn)?;
+            run(coding, |_, s| Complex32::new(s.im, s.re) * turn)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn mirrored() -> Result<()> {
+        // Swapping I and Q mirrors the constellation, which no rotation can
+        // undo.
+        for modulation in MODULATIONS {
+            for n in 0..4 {
+                run(
+                    Coding {
+                        modulation,
+                        rate: CodeRate::Half,
+                        ..UNCODED
+                    },
+                    |_, s| rotate(Complex32::new(s.im, s.re), n),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn pilots() -> Result<()> {
+        // A slip partway through the data, just before the tenth group of
+        // pilots, is put right by it.
+        let coding = Coding {
+            pilot_interval: 8,
+            ..Coding::default()
+        };
+        let data_start = 32 + block_syms(coding.rate.coded_len(HEADER_LEN), 2);
+        let slip = data_start + 10 * 8 + 9 * PILOT.len();
+
+        for modulation in MODULATIONS {
+            for n in [1, 2, 3] {
+                let coding = Coding {
+                    modulation,
+                    ..coding
+                };
+
+                run_payload(coding, (0..100).collect(), |i, s| {
+                    if i >= slip {
+                        rotate(s, n)
+                    } else {
+                        s
+                    }
+                })?;
+            }
+        }
+
+        // 8PSK can also slip by an odd number of eighth turns.
+        let coding = Coding {
+            modulation: Modulation::Psk8,
+            ..coding
+        };
+
+        for n in [1, 3, 5, 7] {
+            let turn = Complex32::from_polar(1.0, -(n as f32) * PI / 4.0);
+
+            run_payload(coding, (0..100).collect(), |i, s| {
+                if i >= slip {
+                    s * turn
+                } else {
+                    s
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn pilot_positions() {
+        let coding = Coding {
+            pilot_interval: 4,
+            ..UNCODED
+        };
+        let mut encoder = FrameEncoder::create(coding);
+        let mut rx = Receiver::new(coding);
+
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+
+        // Sync words and header, then 32 data symbols with seven groups of
+        // pilots between them.
+        let syms: Vec<Complex32> = encoder.sym_queue.iter().map(|x| x.unwrap()).collect();
+        assert_eq!(syms.len(), 32 + HEADER_LEN * 4 + 32 + 7 * PILOT.len());
+
+        for group in 0..7 {
+            let start = 32 + HEADER_LEN * 4 + 4 + group * (4 + PILOT.len());
+            let pilots: Vec<Sym> = syms[start..start + PILOT.len()]
+                .iter()
+                .map(|x| Sym::from_point(*x))
+                .collect();
+            assert_eq!(pilots, PILOT);
+        }
+
+        // Nothing but the pilots can tell the decoder about a slip.
+        let mut frame = None;
+        for (i, x) in syms.iter().enumerate() {
+            frame = rx.push_sym(if i >= 32 + HEADER_LEN * 4 + 8 {
+                rotate(*x, 1)
+            } else {
+                *x
+            });
+        }
+        assert_eq!(rx.demapper.phase_slips, 1);
+        assert!(frame.is_none());
+    }
+
+    #[test]
+    fn noisy_modulations() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let noise = Normal::new(0.0, 0.015).unwrap();
+
+        for modulation in MODULATIONS {
+            let coding = Coding {
+                modulation,
+                ..Coding::default()
+            };
+
+            run_payload(coding, (0..100).collect(), |_, s| {
+                s + Complex32::new(noise.sample(&mut rng), noise.sample(&mut rng))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn dqpsk_cycle_slip() -> Result<()> {
+        let coding = Coding {
+            modulation: Modulation::Dqpsk,
+            ..Coding::default()
+        };
+
+        // The carrier loop slips a quarter turn part way through the data,
+        // which only corrupts the symbol where it happens.
+        run_payload(coding, (0..64).collect(), |i, s| match i {
+            150.. => rotate(s, 1),
+            _ => s,
+        })
+    }
+
+    #[test]
+    fn dqpsk_without_carrier_sync() -> Result<()> {
+        let coding = Coding {
+            modulation: Modulation::Dqpsk,
+            ..Coding::default()
+        };
+
+        // A constellation left spinning by an uncorrected frequency offset.
+        run_payload(coding, (0..100).collect(), |i, s| {
+            s * Complex32::from_polar(1.0, 0.1 + 0.02 * i as f32)
+        })
+    }
+
+    #[test]
+    fn reed_solomon_burst() -> Result<()> {
+        let coding = Coding {
+            reed_solomon: true,
+            ..UNCODED
+        };
+
+        // Wipe out 10 bytes of each of the first two codewords.
+        run_payload(
+            coding,
+            (0..300).map(|x| x as u8).collect(),
+            |i, s| match i {
+                100..=139 | 1200..=1239 => rotate(s, 1),
+                _ => s,
+            },
+        )
+    }
+
+    #[test]
+    fn ldpc_sym_errors() -> Result<()> {
+        let coding = Coding {
+            payload: PayloadCode::Ldpc,
+            ..Coding::default()
+        };
+
+        // A 100 byte payload spans three LDPC codewords.
+        run_payload(coding, (0..100).collect(), |i, s| match i {
+            150 | 200 | 400 | 600 | 800 => -s,
+            _ => s,
+        })
+    }
+
+    #[test]
+    fn interleaved_burst() -> Result<()> {
+        for kind in [InterleaverKind::Block, InterleaverKind::Convolutional] {
+            let coding = Coding {
+                interleaver: Interleaver {
+                    kind,
+                    ..UNCODED.interleaver
+                },
+                ..Coding::default()
+            };
+
+            // Wipe out 12 consecutive data symbols.
+            run_payload(coding, (0..64).collect(), |i, s| match i {
+                150..=161 => -s,
+                _ => s,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn whitened_zeros() {
+        let mut encoder = FrameEncoder::create(Coding::default());
+
+        encoder.push_frame(&[0; 100]);
+
+        let syms: Vec<Complex32> = encoder.sym_queue.iter().map(|s| s.unwrap()).collect();
+        let longest_run = syms
+            .windows(2)
+            .fold((0, 0), |(longest, run), x| {
+                let run = if x[0] == x[1] { run + 1 } else { 0 };
+                (longest.max(run), run)
+            })
+            .0;
+
+        assert!(longest_run < 8);
+    }
+
+    #[test]
+    fn frame_quality() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let sigma = 0.02;
+        let noise = Normal::new(0.0, sigma).unwrap();
+        let mut encoder = FrameEncoder::create(Coding::default());
+        let mut rx = Receiver::new(Coding::default());
+
+        encoder.push_frame(&[0; 100]);
+
+        let frame = encoder.sym_queue.iter().find_map(|sym| {
+            rx.push_sym(
+                sym.unwrap() + Complex32::new(noise.sample(&mut rng), noise.sample(&mut rng)),
+            )
+        });
+
+        let frame = frame.unwrap();
+        let quality = frame.quality.unwrap();
+        let evm = (2.0 * sigma * sigma).sqrt() / MAGNITUDE;
+
+        assert_eq!(frame.payload, vec![0; 100]);
+        assert!((quality.evm - evm).abs() < 0.1 * evm);
+    }
+
+    #[test]
+    fn sync_in_payload() -> Result<()> {
+        // Without any coding each byte of the payload is carried by four
+        // symbols, scrambled by the same sequence whatever the payload.
+        let mut zeros = FrameEncoder::create(UNCODED);
+        zeros.push_frame(&[0; 16]);
+
+        let start = zeros.sym_queue.len() - (16 + CRC_LEN) * 4;
+        let mut payload = vec![0; 16];
+
+        // Choose bytes 4 to 7 of the payload so that they're sent as the
+        // sync word.
+        for (i, sync) in SYNC.iter().enumerate() {
+            let j = 16 + i;
+            let scrambled = u8::from(Sym::from_point(zeros.sym_queue[start + j].unwrap()));
+
+            payload[j / 4] |= (scrambled ^ u8::from(*sync)) << (6 - 2 * (j % 4));
+        }
+
+        let mut encoder = FrameEncoder::create(UNCODED);
+        encoder.push_frame(&payload);
+
+        let sent: Vec<Sym> = encoder
+            .sym_queue
+            .range(start + 16..start + 32)
+            .map(|x| Sym::from_point(x.unwrap()))
+            .collect();
+        assert_eq!(sent, SYNC);
+
+        run_payload(UNCODED, payload, |_, s| s)
+    }
+
+    #[test]
+    fn oversized_frame_dropped() {
+        let mut encoder = FrameEncoder::create(UNCODED);
+        let mut rx = Receiver::new(UNCODED);
+
+        encoder.push_frame(&[0; MAX_FRAME_SZ + 1]);
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+
+        let frames: Vec<Vec<u8>> = encoder
+            .sym_queue
+            .iter()
+            .filter_map(|sym| rx.push_sym(sym.unwrap()))
+            .map(|frame| frame.payload)
+            .collect();
+
+        assert_eq!(frames, vec![vec![0xde, 0xad, 0xbe, 0xef]]);
+        assert_eq!(rx.decoder.header_errors, 1);
+    }
+
+    #[test]
+    fn corrupt_frame_dropped() {
+        let mut encoder = FrameEncoder::create(UNCODED);
+        let mut rx = Receiver::new(UNCODED);
+
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+
+        // Corrupt the final symbol of the payload.
+        let corrupt_idx = encoder.sym_queue.len() - 17;
+        let sym = encoder.sym_queue[corrupt_idx].unwrap();
+        encoder.sym_queue[corrupt_idx] = Some(rotate(sym, 1));
+
+        for sym in encoder.sym_queue.iter() {
+            assert!(rx.push_sym(sym.unwrap()).is_none());
+        }
+
+        assert_eq!(rx.decoder.crc_errors, 1);
+    }
+
+    /// Receive the symbols of a frame with those of the coded data in
+    /// `erased` lost in a fade, returning the frame if it is decoded.
+    fn receive_erased(
+        rx: &mut Receiver,
+        syms: &[Symbol],
+        erased: std::ops::Range<usize>,
+    ) -> Option<Frame> {
+        syms.iter()
+            .enumerate()
+            .filter_map(|(i, sym)| {
+                let x = if erased.contains(&i) {
+                    Complex32::new(0.0, 0.0)
+                } else {
+                    sym.unwrap()
+                };
+
+                rx.push_sym(x)
+            })
+            .last()
+    }
+
+    /// The symbols of a frame sent reliably by ARQ from station `src`.
+    fn arq_frame_syms(encoder: &mut FrameEncoder, seq: u16, src: u16) -> Vec<Symbol> {
+        encoder.encode(&Frame {
+            frame_type: FrameType::Data,
+            seq,
+            flags: FLAG_ARQ,
+            src,
+            payload: vec![0xde, 0xad, 0xbe, 0xef],
+            quality: None,
+        });
+
+        encoder.sym_queue.drain(..).collect()
+    }
+
+    #[test]
+    fn harq_combining() {
+        let coding = Coding {
+            rate: CodeRate::Half,
+            ..UNCODED
+        };
+        let mut encoder = FrameEncoder::create(coding);
+        let mut rx = Receiver::new(coding);
+
+        // The data starts after the sync words and the 102 symbols of the
+        // header.  Each reception loses a different half of it, so neither
+        // can be decoded alone.
+        let syms = arq_frame_syms(&mut encoder, 0, 1);
+        let mid = (134 + syms.len()) / 2;
+
+        assert_eq!(receive_erased(&mut rx, &syms, 134..mid), None);
+        assert_eq!(rx.decoder.harq.len(), 1);
+
+        let frame = receive_erased(&mut rx, &syms, mid..syms.len());
+        assert_eq!(frame.map(|f| f.payload), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(rx.decoder.crc_errors, 1);
+        assert_eq!(rx.decoder.harq_recoveries, 1);
+        assert!(rx.decoder.harq.is_empty());
+    }
+
+    #[test]
+    fn harq_different_frame() {
+        let coding = Coding {
+            rate: CodeRate::Half,
+            ..UNCODED
+        };
+        let mut encoder = FrameEncoder::create(coding);
+
+        // Neither a frame with a different sequence number nor one from a
+        // different station is combined with the first.
+        for (seq, src) in [(1, 1), (0, 2)] {
+            let mut rx = Receiver::new(coding);
+            let first = arq_frame_syms(&mut encoder, 0, 1);
+            let second = arq_frame_syms(&mut encoder, seq, src);
+            let mid = (134 + first.len()) / 2;
+
+            assert_eq!(receive_erased(&mut rx, &first, 134..mid), None);
+            assert_eq!(receive_erased(&mut rx, &second, mid..second.len()), None);
+            assert_eq!(rx.decoder.harq.len(), 2);
+            assert_eq!(rx.decoder.harq_recoveries, 0);
+        }
+    }
+
+    #[test]
+    fn harq_only_arq() {
+        let coding = Coding {
+            rate: CodeRate::Half,
+            ..UNCODED
+        };
+        let mut encoder = FrameEncoder::create(coding);
+        let mut rx = Receiver::new(coding);
+
+        // Frames sent without ARQ are never retransmitted.
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+        let syms: Vec<Symbol> = encoder.sym_queue.drain(..).collect();
+
+        assert_eq!(receive_erased(&mut rx, &syms, 134..syms.len()), None);
+        assert_eq!(rx.decoder.crc_errors, 1);
+        assert!(rx.decoder.harq.is_empty());
+    }
+
+    #[test]
+    fn corrupt_header_dropped() {
+        let mut encoder = FrameEncoder::create(UNCODED);
+        let mut rx = Receiver::new(UNCODED);
+
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+
+        // Corrupt the payload length, which is followed by the data of the
+        // frame; the decoder gives up on it straight after the header.
+        let corrupt_idx = 32 + 26;
+        let sym = encoder.sym_queue[corrupt_idx].unwrap();
+        encoder.sym_queue[corrupt_idx] = Some(rotate(sym, 1));
+
+        for (i, sym) in encoder.sym_queue.iter().enumerate() {
+            rx.push_sym(sym.unwrap());
+
+            if i == 32 + HEADER_LEN * 4 - 1 {
+                assert_eq!(rx.decoder.header_errors, 1);
+                assert!(matches!(rx.decoder.state, DecoderState::Sync));
+            }
+        }
+
+        assert_eq!(rx.decoder.crc_errors, 0);
+    }
+
+    #[test]
+    fn header_fields() {
+        let mut encoder = FrameEncoder::create(UNCODED);
+        let mut rx = Receiver::new(UNCODED);
+        let frame = Frame {
+            frame_type: FrameType::Control,
+            seq: 0xbeef,
+            flags: 0x81,
+            src: 0x1234,
+            payload: vec![1, 2, 3],
+            quality: None,
+        };
+
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+        encoder.encode(&frame);
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+
+        let frames: Vec<Frame> = encoder
+            .sym_queue
+            .iter()
+            .filter_map(|sym| rx.push_sym(sym.unwrap()))
+            .collect();
+
+        // Packets posted to the encoder are numbered separately from the
+        // frames it is given.
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            Frame {
+                quality: None,
+                ..frames[1].clone()
+            },
+            frame
+        );
+        assert!(frames.iter().all(|f| f.quality.is_some()));
+        assert_eq!(
+            frames.iter().map(|f| f.seq).collect::<Vec<_>>(),
+            [0, 0xbeef, 1]
+        );
+        assert_eq!(frames[2].frame_type, FrameType::Data);
+    }
+
+    #[test]
+    fn receiving() {
+        let mut encoder = FrameEncoder::create(UNCODED);
+        let mut rx = Receiver::new(UNCODED);
+
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+
+        let receiving: Vec<bool> = encoder
+            .sym_queue
+            .iter()
+            .map(|sym| {
+                rx.push_sym(sym.unwrap());
+                rx.decoder.receiving()
+            })
+            .collect();
+        let n = receiving.len();
+
+        // From the end of the first sync word up to the last symbol of the
+        // frame.
+        assert!(receiving[..SYNC.len() - 1].iter().all(|r| !r));
+        assert!(receiving[SYNC.len() - 1..n - 1].iter().all(|r| *r));
+        assert!(!receiving[n - 1]);
+    }
+
+    #[test]
+    fn coding_mismatch() {
+        let coding = Coding {
+            modulation: Modulation::Qam16,
+            ..UNCODED
+        };
+        let mut encoder = FrameEncoder::create(UNCODED);
+        let mut rx = Receiver::new(coding);
+
+        encoder.push_frame(&[0xde, 0xad, 0xbe, 0xef]);
+
+        for sym in encoder.sym_queue.iter() {
+            assert!(rx.push_sym(sym.unwrap()).is_none());
+        }
+
+        assert_eq!(rx.decoder.header_errors, 1);
+        assert_ne!(UNCODED.id(), coding.id());
+    }
+
+    #[test]
+    fn simulated_link() -> Result<()> {
+        const SAMPLE_RATE: f32 = 800_000.0;
+
+        let coding = Coding::default();
+        let pulse = PulseShape {
+            rrc: true,
+            ..PulseShape::default()
+        };
+        let params = ChannelParams {
+            noise: 0.03,
+            freq_offset: 500.0,
+            phase_noise: 1e-3,
+            drift_ppm: 50.0,
+            taps: vec![Complex32::new(1.0, 0.0), Complex32::new(0.2, 0.1)],
+            seed: 1,
+        };
+
+        let payloads: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 50]).collect();
+        let mut encoder = FrameEncoder::create(coding);
+        let mut syms: Vec<Symbol> = vec![None; 100];
+
+        for payload in payloads.iter() {
+            encoder.push_frame(payload);
+            syms.extend(encoder.sym_queue.drain(..));
+            syms.extend([None; 100]);
+        }
+
+        let mut fg = Flowgraph::new();
+
+        let src = VectorSource::new(syms);
+        let qam_mod = QamMod::new(10, pulse);
+        let channel = Channel::new(params, SAMPLE_RATE);
+        let freq_sync = FreqSync::new(SAMPLE_RATE, coding.modulation);
+        let matched_filter = pulse.matched_filter(10);
+        let agc = Agc::new(AgcParams::default());
+        let clock_sync = ClockSync::new(10.0, 0.01, 0.707);
+        let equaliser = Equaliser::new(EqualiserParams::default(), coding.modulation);
+        let carrier_sync = CarrierSync::new(0.02, 0.707, coding.modulation);
+        let qam_demod = QamDemod::new(coding, DEFAULT_THRESHOLD);
+        let hard_sink = NullSink::<Symbol>::new();
+        let frame_decoder = FrameDecoder::new(coding);
+        let (tx, rx) = mpsc::channel::<Pmt>(100);
+        let message_sink = MessagePipe::new(tx);
+
+        connect!(fg, src > qam_mod > channel > freq_sync > matched_filter > agc > clock_sync > equaliser > carrier_sync > qam_demod > hard_sink;
+                 qam_demod.soft > frame_decoder | message_sink;
+                 frame_decoder.data | qam_demod.data);
+
+        Runtime::new().run(fg)?;
+
+        let received: Vec<Vec<u8>> = futuresdr::futures::executor::block_on(
+            rx.filter_map(|p| async move {
+                match p {
+                    Pmt::Blob(data) => Some(data),
+                    _ => None,
+                }
+            })
+            .collect(),
+        );
+
+        // The first frame is lost while the frequency, timing and carrier
+        // loops lock.
+        assert_eq!(received, payloads[1..]);
+
+        Ok(())
     }
 }
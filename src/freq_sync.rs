@@ -0,0 +1,273 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    num_complex::Complex32,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
+use rustfft::{Fft, FftPlanner};
+
+use crate::constellation::Modulation;
+
+/// Number of samples over which each frequency estimate is made.
+const WINDOW: usize = 4096;
+
+/// Smallest fraction of the power of the `M`th power signal that must fall
+/// in the peak bin for a window to be considered as containing a signal.
+/// Noise alone spreads its power roughly evenly over all `WINDOW` bins.
+const MIN_PEAK: f32 = 0.05;
+
+/// Weight given to the estimates from previous windows.
+const MEMORY: f32 = 0.5;
+
+/// Coarse carrier frequency offset acquisition.  Raising the samples to the
+/// `M`th power, where `M` is the symmetry of the modulation (four, or eight
+/// for 8PSK), removes the modulation, leaving a tone at `M` times the
+/// frequency offset which is found with an FFT.  The stream is de-rotated by
+/// the estimated offset so that only a small residual is left for the
+/// carrier sync block to track.
+///
+/// The estimate is unambiguous up to the sample rate divided by `2M`.  Every
+/// new estimate is posted, in Hz, to the `freq` message output.
+pub struct FreqSync {
+    sample_rate: f32,
+    fft: Arc<dyn Fft<f32>>,
+    /// Power the samples are raised to.
+    power: i32,
+    /// The samples in the current window raised to `power`.
+    buf: Vec<Complex32>,
+    /// Whether a signal has been seen yet.
+    acquired: bool,
+    /// Estimated frequency offset, in radians per sample.
+    freq: f32,
+    phase: f32,
+}
+
+impl FreqSync {
+    pub fn new(sample_rate: f32, modulation: Modulation) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("FreqSync").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .add_output("out", std::mem::size_of::<Complex32>())
+                .build(),
+            MessageIoBuilder::new().add_output("freq").build(),
+            Self::create(sample_rate, modulation),
+        )
+    }
+
+    fn create(sample_rate: f32, modulation: Modulation) -> Self {
+        FreqSync {
+            sample_rate,
+            fft: FftPlanner::new().plan_fft_forward(WINDOW),
+            power: modulation.symmetry() as i32,
+            buf: Vec::with_capacity(WINDOW),
+            acquired: false,
+            freq: 0.0,
+            phase: 0.0,
+        }
+    }
+
+    /// Estimated frequency offset in Hz.
+    fn offset_hz(&self) -> f32 {
+        self.freq * self.sample_rate / (2.0 * PI)
+    }
+
+    /// Frequency, in radians per sample, of the strongest tone in the signal
+    /// raised to `power`, divided by `power`.  Returns `None` if there is no
+    /// significant tone.
+    fn estimate(&mut self) -> Option<f32> {
+        self.fft.process(&mut self.buf);
+
+        let power: Vec<f32> = self.buf.iter().map(|x| x.norm_sqr()).collect();
+        let total: f32 = power.iter().sum();
+        let (peak, peak_power) =
+            power.iter().enumerate().fold(
+                (0, 0.0),
+                |acc, (i, p)| if *p > acc.1 { (i, *p) } else { acc },
+            );
+
+        if peak_power <= MIN_PEAK * total {
+            return None;
+        }
+
+        // Refine the peak by fitting a parabola through the magnitudes of
+        // the neighbouring bins.
+        let mag = |i: usize| power[i % WINDOW].sqrt();
+        let (a, b, c) = (mag(peak + WINDOW - 1), mag(peak), mag(peak + 1));
+        let delta = 0.5 * (a - c) / (a - 2.0 * b + c);
+
+        let bin = if peak < WINDOW / 2 {
+            peak as f32
+        } else {
+            peak as f32 - WINDOW as f32
+        };
+
+        Some(2.0 * PI * (bin + delta) / WINDOW as f32 / self.power as f32)
+    }
+
+    /// De-rotate a single sample, returning a new estimate (in Hz) at the end
+    /// of each window containing a signal.
+    fn process(&mut self, x: Complex32, out: &mut Complex32) -> Option<f32> {
+        *out = x * Complex32::from_polar(1.0, -self.phase);
+        self.phase = (self.phase + self.freq + PI).rem_euclid(2.0 * PI) - PI;
+
+        self.buf.push(x.powi(self.power));
+
+        if self.buf.len() < WINDOW {
+            return None;
+        }
+
+        let estimate = self.estimate();
+        self.buf.clear();
+
+        let freq = estimate?;
+
+        self.freq = if self.acquired {
+            self.freq * MEMORY + freq * (1.0 - MEMORY)
+        } else {
+            freq
+        };
+        self.acquired = true;
+
+        Some(self.offset_hz())
+    }
+}
+
+#[async_trait]
+impl Kernel for FreqSync {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let input = sio.input(0).slice::<Complex32>();
+        let output = sio.output(0).slice::<Complex32>();
+        let n = input.len().min(output.len());
+        let mut estimates = Vec::new();
+
+        for (i, o) in input.iter().zip(output.iter_mut()) {
+            if let Some(hz) = self.process(*i, o) {
+                estimates.push(hz);
+            }
+        }
+
+        for hz in estimates {
+            mio.post(0, Pmt::F32(hz)).await;
+        }
+
+        if sio.input(0).finished() && n == input.len() {
+            io.finished = true;
+        }
+
+        sio.input(0).consume(n);
+        sio.output(0).produce(n);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use futuresdr::num_complex::Complex32;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rand_distr::{Distribution, Normal};
+
+    use crate::{constellation::Modulation, pulse_shape::PulseShape, qam::QamMod, sym::Sym};
+
+    use super::{FreqSync, WINDOW};
+
+    const SAMPLE_RATE: f32 = 800_000.0;
+
+    /// Returns the estimates and the de-rotated samples.
+    fn run(samples: &[Complex32], modulation: Modulation) -> (Vec<f32>, Vec<Complex32>) {
+        let mut fs = FreqSync::create(SAMPLE_RATE, modulation);
+        let mut out = vec![Complex32::new(0.0, 0.0); samples.len()];
+        let estimates = samples
+            .iter()
+            .zip(out.iter_mut())
+            .filter_map(|(x, o)| fs.process(*x, o))
+            .collect();
+
+        (estimates, out)
+    }
+
+    #[test]
+    fn measures_offset() {
+        for pulse in [
+            PulseShape::default(),
+            PulseShape {
+                rrc: true,
+                ..PulseShape::default()
+            },
+        ] {
+            for offset in [-25_000.0, -3_000.0, 0.0, 4_500.0, 60_000.0] {
+                let w = 2.0 * PI * offset / SAMPLE_RATE;
+                let samples: Vec<Complex32> = QamMod::modulate(10, pulse, &Sym::random(2000))
+                    .iter()
+                    .enumerate()
+                    .map(|(n, x)| x * Complex32::from_polar(1.0, w * n as f32))
+                    .collect();
+
+                let (estimates, out) = run(&samples, Modulation::Qpsk);
+
+                assert!(estimates.len() >= 4);
+                assert!(estimates.iter().all(|hz| (hz - offset).abs() < 100.0));
+
+                // Once a signal has been seen the output is rotated back by
+                // the offset.
+                for n in 4 * WINDOW..4 * WINDOW + 1000 {
+                    let rotation = out[n] / samples[n];
+                    let step = (out[n + 1] / samples[n + 1] / rotation).arg();
+
+                    assert!((step + w).abs() < 2.0 * PI * 250.0 / SAMPLE_RATE);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_noise() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let noise = Normal::new(0.0, 0.3).unwrap();
+        let samples: Vec<Complex32> = (0..10 * WINDOW)
+            .map(|_| Complex32::new(noise.sample(&mut rng), noise.sample(&mut rng)))
+            .collect();
+
+        assert!(run(&samples, Modulation::Qpsk).0.is_empty());
+        assert!(run(&samples, Modulation::Psk8).0.is_empty());
+    }
+
+    #[test]
+    fn measures_psk8_offset() {
+        // The fourth power of 8PSK is BPSK, which has no tone.
+        let mut rng = StdRng::seed_from_u64(1);
+        let points = Modulation::Psk8.constellation().points().to_vec();
+        let syms: Vec<Complex32> = (0..2000)
+            .map(|_| points[rng.gen_range(0..points.len())])
+            .collect();
+
+        for offset in [-25_000.0, 0.0, 4_500.0, 40_000.0] {
+            let w = 2.0 * PI * offset / SAMPLE_RATE;
+            let samples: Vec<Complex32> = syms
+                .iter()
+                .flat_map(|x| [*x; 10])
+                .enumerate()
+                .map(|(n, x)| x * Complex32::from_polar(1.0, w * n as f32))
+                .collect();
+
+            let (estimates, _) = run(&samples, Modulation::Psk8);
+
+            assert!(estimates.len() >= 4);
+            assert!(estimates.iter().all(|hz| (hz - offset).abs() < 100.0));
+        }
+    }
+}
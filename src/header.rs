@@ -0,0 +1,223 @@
+use std::fmt;
+
+use futuresdr::runtime::Pmt;
+
+use crate::crc::crc16;
+use crate::link_quality::Quality;
+
+/// Version of the header format.  Frames with any other version are dropped.
+pub const HEADER_VERSION: u8 = 1;
+
+/// Size in bytes of the frame header, including its CRC.
+pub const HEADER_LEN: usize = 12;
+
+/// What a frame carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// A packet from the network interface.
+    Data,
+    /// Link control, such as an acknowledgement.
+    Control,
+}
+
+/// Header sent, convolutionally coded and with QPSK, between the sync words
+/// and the data of every frame.  It is laid out as the version, frame type,
+/// sequence number, flags, payload coding ID, payload length and source
+/// station, with multi-byte fields big endian, followed by a CRC-16 of all of
+/// them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub seq: u16,
+    pub flags: u8,
+    /// The `Coding::id` of the data.
+    pub coding_id: u8,
+    /// Length of the payload, in bytes.
+    pub len: u16,
+    /// Identifies the station that sent the frame, so that frames from
+    /// different stations with otherwise identical headers can be told apart.
+    pub src: u16,
+}
+
+/// A frame as passed between the frame encoder and decoder and the blocks
+/// above them, with the fields of the header that aren't filled in by the
+/// encoder itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub seq: u16,
+    pub flags: u8,
+    /// The `FrameHeader::src` of the frame, zero when the sender doesn't
+    /// identify itself.
+    pub src: u16,
+    pub payload: Vec<u8>,
+    /// Quality of the symbols the frame was received in, filled in by the
+    /// decoder.
+    pub quality: Option<Quality>,
+}
+
+impl Frame {
+    pub fn to_pmt(self) -> Pmt {
+        Pmt::Any(Box::new(self))
+    }
+
+    pub fn from_pmt(p: &Pmt) -> Option<Self> {
+        match p {
+            Pmt::Any(a) => a.downcast_ref::<Frame>().cloned(),
+            _ => None,
+        }
+    }
+}
+
+/// Why a received header was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    Crc,
+    Version(u8),
+    FrameType(u8),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Crc => write!(f, "bad header CRC"),
+            HeaderError::Version(v) => write!(f, "unsupported header version {}", v),
+            HeaderError::FrameType(t) => write!(f, "unknown frame type {}", t),
+        }
+    }
+}
+
+impl FrameHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut ret = [0; HEADER_LEN];
+        let frame_type = match self.frame_type {
+            FrameType::Data => 0,
+            FrameType::Control => 1,
+        };
+
+        ret[0] = HEADER_VERSION;
+        ret[1] = frame_type;
+        ret[2..4].copy_from_slice(&self.seq.to_be_bytes());
+        ret[4] = self.flags;
+        ret[5] = self.coding_id;
+        ret[6..8].copy_from_slice(&self.len.to_be_bytes());
+        ret[8..10].copy_from_slice(&self.src.to_be_bytes());
+
+        let crc = crc16(&ret[..HEADER_LEN - 2]);
+        ret[HEADER_LEN - 2..].copy_from_slice(&crc.to_be_bytes());
+
+        ret
+    }
+
+    /// Parse a received header, checking its CRC before anything else.
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Result<Self, HeaderError> {
+        let crc = u16::from_be_bytes([bytes[HEADER_LEN - 2], bytes[HEADER_LEN - 1]]);
+
+        if crc != crc16(&bytes[..HEADER_LEN - 2]) {
+            return Err(HeaderError::Crc);
+        }
+
+        if bytes[0] != HEADER_VERSION {
+            return Err(HeaderError::Version(bytes[0]));
+        }
+
+        let frame_type = match bytes[1] {
+            0 => FrameType::Data,
+            1 => FrameType::Control,
+            t => return Err(HeaderError::FrameType(t)),
+        };
+
+        Ok(FrameHeader {
+            frame_type,
+            seq: u16::from_be_bytes([bytes[2], bytes[3]]),
+            flags: bytes[4],
+            coding_id: bytes[5],
+            len: u16::from_be_bytes([bytes[6], bytes[7]]),
+            src: u16::from_be_bytes([bytes[8], bytes[9]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futuresdr::runtime::Pmt;
+
+    use crate::crc::crc16;
+
+    use super::{Frame, FrameHeader, FrameType, HeaderError, HEADER_LEN};
+
+    const HEADER: FrameHeader = FrameHeader {
+        frame_type: FrameType::Control,
+        seq: 0x1234,
+        flags: 0x80,
+        coding_id: 0x21,
+        len: 1500,
+        src: 0xbeef,
+    };
+
+    /// Replace the CRC of a header after changing it.
+    fn fix_crc(bytes: &mut [u8; HEADER_LEN]) {
+        let crc = crc16(&bytes[..HEADER_LEN - 2]);
+        bytes[HEADER_LEN - 2..].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = HEADER.to_bytes();
+
+        assert_eq!(
+            bytes[..10],
+            [1, 1, 0x12, 0x34, 0x80, 0x21, 0x05, 0xdc, 0xbe, 0xef]
+        );
+        assert_eq!(FrameHeader::from_bytes(&bytes), Ok(HEADER));
+    }
+
+    #[test]
+    fn bad_crc() {
+        for i in 0..HEADER_LEN * 8 {
+            let mut bytes = HEADER.to_bytes();
+            bytes[i / 8] ^= 1 << (i % 8);
+
+            assert_eq!(FrameHeader::from_bytes(&bytes), Err(HeaderError::Crc));
+        }
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut bytes = HEADER.to_bytes();
+        bytes[0] = 2;
+        fix_crc(&mut bytes);
+
+        assert_eq!(
+            FrameHeader::from_bytes(&bytes),
+            Err(HeaderError::Version(2))
+        );
+    }
+
+    #[test]
+    fn bad_frame_type() {
+        let mut bytes = HEADER.to_bytes();
+        bytes[1] = 7;
+        fix_crc(&mut bytes);
+
+        assert_eq!(
+            FrameHeader::from_bytes(&bytes),
+            Err(HeaderError::FrameType(7))
+        );
+    }
+
+    #[test]
+    fn frame_pmt() {
+        let frame = Frame {
+            frame_type: FrameType::Data,
+            seq: 7,
+            flags: 1,
+            src: 0x1234,
+            payload: vec![0xde, 0xad, 0xbe, 0xef],
+            quality: None,
+        };
+
+        assert_eq!(Frame::from_pmt(&frame.clone().to_pmt()), Some(frame));
+        assert_eq!(Frame::from_pmt(&Pmt::Blob(vec![0xde])), None);
+    }
+}
@@ -0,0 +1,170 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InterleaverKind {
+    None,
+    /// Symbols are written into a matrix row by row and read out column by
+    /// column.
+    Block,
+    /// Forney convolutional interleaver: symbols are distributed over
+    /// branches with increasing delay.
+    Convolutional,
+}
+
+/// Interleaves the coded symbols of a frame so that a burst of corrupted
+/// symbols on air is spread out over the codeword.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::Args)]
+pub struct Interleaver {
+    #[arg(long = "interleaver", value_enum, default_value = "none")]
+    pub kind: InterleaverKind,
+    /// Number of rows of the block interleaver or branches of the
+    /// convolutional interleaver.
+    #[arg(
+        long = "interleaver-depth",
+        default_value_t = 16,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub depth: usize,
+    /// Additional delay, in symbols, of each successive branch of the
+    /// convolutional interleaver.  A delay of zero wouldn't interleave at all.
+    #[arg(
+        long = "interleaver-delay",
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub delay: usize,
+}
+
+impl Default for Interleaver {
+    fn default() -> Self {
+        Self {
+            kind: InterleaverKind::None,
+            depth: 16,
+            delay: 1,
+        }
+    }
+}
+
+impl Interleaver {
+    /// Number of symbols output when interleaving `n` symbols.  The
+    /// convolutional interleaver has to be flushed at the end of each frame,
+    /// which costs some extra symbols.
+    pub fn interleaved_len(&self, n: usize) -> usize {
+        match self.kind {
+            InterleaverKind::Convolutional if n > 0 => {
+                n + (self.depth - 1) * self.depth * self.delay
+            }
+            _ => n,
+        }
+    }
+
+    /// For each output symbol, the index of the input symbol that is sent in
+    /// that position (if any).
+    fn order(&self, n: usize) -> Vec<Option<usize>> {
+        match self.kind {
+            InterleaverKind::None => (0..n).map(Some).collect(),
+            InterleaverKind::Block => {
+                let cols = n.div_ceil(self.depth);
+
+                (0..cols)
+                    .flat_map(|c| (0..self.depth).map(move |r| r * cols + c))
+                    .filter(|i| *i < n)
+                    .map(Some)
+                    .collect()
+            }
+            InterleaverKind::Convolutional => {
+                let mut ret = vec![None; self.interleaved_len(n)];
+
+                (0..n).for_each(|i| {
+                    ret[i + (i % self.depth) * self.depth * self.delay] = Some(i);
+                });
+
+                ret
+            }
+        }
+    }
+
+    /// Interleave `syms`, using `fill` for any positions that don't carry a
+    /// symbol.
+    pub fn interleave<T: Clone>(&self, syms: &[T], fill: T) -> Vec<T> {
+        self.order(syms.len())
+            .into_iter()
+            .map(|i| i.map_or_else(|| fill.clone(), |i| syms[i].clone()))
+            .collect()
+    }
+
+    /// Recover the `n` symbols that were interleaved to produce `syms`.
+    pub fn deinterleave<T: Clone>(&self, syms: &[T], n: usize) -> Vec<T> {
+        assert_eq!(syms.len(), self.interleaved_len(n));
+
+        let mut ret = vec![None; n];
+
+        self.order(n)
+            .into_iter()
+            .zip(syms)
+            .filter_map(|(i, s)| Some((i?, s)))
+            .for_each(|(i, s)| ret[i] = Some(s.clone()));
+
+        ret.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interleaver, InterleaverKind};
+
+    fn interleaver(kind: InterleaverKind) -> Interleaver {
+        Interleaver {
+            kind,
+            depth: 4,
+            delay: 2,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for kind in [
+            InterleaverKind::None,
+            InterleaverKind::Block,
+            InterleaverKind::Convolutional,
+        ] {
+            let il = interleaver(kind);
+
+            for n in [0, 1, 7, 16, 50] {
+                let syms: Vec<usize> = (0..n).collect();
+                let interleaved = il.interleave(&syms, usize::MAX);
+
+                assert_eq!(interleaved.len(), il.interleaved_len(n));
+                assert_eq!(il.deinterleave(&interleaved, n), syms);
+            }
+        }
+    }
+
+    #[test]
+    fn block_order() {
+        let syms: Vec<usize> = (0..10).collect();
+
+        // 4 rows of 3 columns, the last row only has one entry.
+        assert_eq!(
+            interleaver(InterleaverKind::Block).interleave(&syms, usize::MAX),
+            vec![0, 3, 6, 9, 1, 4, 7, 2, 5, 8]
+        );
+    }
+
+    #[test]
+    fn spreads_bursts() {
+        for kind in [InterleaverKind::Block, InterleaverKind::Convolutional] {
+            let il = interleaver(kind);
+            let syms: Vec<usize> = (0..64).collect();
+            let interleaved = il.interleave(&syms, usize::MAX);
+
+            // Any burst of `depth` consecutive symbols on air must come from
+            // input symbols that are at least `depth` apart.
+            for burst in interleaved.windows(il.depth) {
+                let mut burst: Vec<usize> =
+                    burst.iter().copied().filter(|x| *x != usize::MAX).collect();
+                burst.sort();
+
+                assert!(burst.windows(2).all(|x| x[1] - x[0] >= il.depth));
+            }
+        }
+    }
+}
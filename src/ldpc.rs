@@ -0,0 +1,295 @@
+use crate::fec::{bits_from_bytes, bytes_from_bits};
+
+/// IEEE 802.11n rate 1/2 LDPC code with a codeword length of 648 bits.
+const Z: usize = 27;
+const BASE_ROWS: usize = 12;
+const BASE_COLS: usize = 24;
+
+/// Information bits per codeword.
+pub const K: usize = (BASE_COLS - BASE_ROWS) * Z;
+/// Bits per codeword.
+pub const N: usize = BASE_COLS * Z;
+
+const MAX_ITER: usize = 50;
+/// Normalisation factor applied to the check node messages of the min-sum
+/// decoder.
+const MIN_SUM_SCALE: f32 = 0.75;
+/// Confidence given to the known zero bits used to shorten the final
+/// codeword of a block.
+const SHORTENED_LLR: f32 = 1000.0;
+
+/// Circulant shift of each Z x Z sub-matrix of the parity check matrix, -1
+/// for the all zero matrix.
+#[rustfmt::skip]
+const BASE: [[i8; BASE_COLS]; BASE_ROWS] = [
+    [ 0, -1, -1, -1,  0,  0, -1, -1,  0, -1, -1,  0,  1,  0, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [22,  0, -1, -1, 17, -1,  0,  0, 12, -1, -1, -1, -1,  0,  0, -1, -1, -1, -1, -1, -1, -1, -1, -1],
+    [ 6, -1,  0, -1, 10, -1, -1, -1, 24, -1,  0, -1, -1, -1,  0,  0, -1, -1, -1, -1, -1, -1, -1, -1],
+    [ 2, -1, -1,  0, 20, -1, -1, -1, 25,  0, -1, -1, -1, -1, -1,  0,  0, -1, -1, -1, -1, -1, -1, -1],
+    [23, -1, -1, -1,  3, -1, -1, -1,  0, -1,  9, 11, -1, -1, -1, -1,  0,  0, -1, -1, -1, -1, -1, -1],
+    [24, -1, 23,  1, 17, -1,  3, -1, 10, -1, -1, -1, -1, -1, -1, -1, -1,  0,  0, -1, -1, -1, -1, -1],
+    [25, -1, -1, -1,  8, -1, -1, -1,  7, 18, -1, -1,  0, -1, -1, -1, -1, -1,  0,  0, -1, -1, -1, -1],
+    [13, 24, -1, -1,  0, -1,  8, -1,  6, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,  0,  0, -1, -1, -1],
+    [ 7, 20, -1, 16, 22, 10, -1, -1, 23, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,  0,  0, -1, -1],
+    [11, -1, -1, -1, 19, -1, -1, -1, 13, -1,  3, 17, -1, -1, -1, -1, -1, -1, -1, -1, -1,  0,  0, -1],
+    [25, -1,  8, -1, 23, 18, -1, 14,  9, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,  0,  0],
+    [ 3, -1, -1, -1, 16, -1, -1,  2, 25,  5, -1, -1,  1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,  0],
+];
+
+/// The bit positions taking part in each of the parity checks.
+fn checks() -> Vec<Vec<usize>> {
+    (0..BASE_ROWS * Z)
+        .map(|row| {
+            let (base_row, r) = (row / Z, row % Z);
+
+            BASE[base_row]
+                .iter()
+                .enumerate()
+                .filter(|(_, shift)| **shift >= 0)
+                .map(|(col, shift)| col * Z + (r + *shift as usize) % Z)
+                .collect()
+        })
+        .collect()
+}
+
+/// Multiply a Z bit vector by a circulant sub-matrix.
+fn shift(x: &[bool], shift: i8) -> Vec<bool> {
+    (0..Z).map(|r| x[(r + shift as usize) % Z]).collect()
+}
+
+fn xor(a: &mut [bool], b: &[bool]) {
+    a.iter_mut().zip(b).for_each(|(a, b)| *a ^= b);
+}
+
+/// Systematically encode a single codeword.  The parity part of the base
+/// matrix has the 802.11n dual diagonal structure which allows the parity bits
+/// to be found by back substitution.
+fn encode_block(info: &[bool]) -> Vec<bool> {
+    assert_eq!(info.len(), K);
+
+    let info_cols = BASE_COLS - BASE_ROWS;
+
+    let lambda: Vec<Vec<bool>> = BASE
+        .iter()
+        .map(|row| {
+            let mut acc = vec![false; Z];
+
+            row[..info_cols]
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| **s >= 0)
+                .for_each(|(col, s)| xor(&mut acc, &shift(&info[col * Z..(col + 1) * Z], *s)));
+
+            acc
+        })
+        .collect();
+
+    let mut parity = vec![vec![false; Z]; BASE_ROWS];
+
+    // Summing every row cancels out all of the parity bits but the first.
+    lambda.iter().for_each(|l| xor(&mut parity[0], l));
+
+    for row in 0..BASE_ROWS - 1 {
+        let mut p = lambda[row].clone();
+
+        if row == 0 {
+            xor(&mut p, &shift(&parity[0], BASE[row][info_cols]));
+        } else {
+            xor(&mut p, &parity[row]);
+
+            if BASE[row][info_cols] >= 0 {
+                xor(&mut p, &shift(&parity[0], BASE[row][info_cols]));
+            }
+        }
+
+        parity[row + 1] = p;
+    }
+
+    let mut ret = info.to_vec();
+    parity.iter().for_each(|p| ret.extend(p));
+    ret
+}
+
+/// Number of bits transmitted for a block of `n_bytes` bytes.  The final
+/// codeword is shortened: the zero padding needed to fill its information
+/// bits is not transmitted.
+pub fn coded_len(n_bytes: usize) -> usize {
+    n_bytes * 8 + (n_bytes * 8).div_ceil(K) * (N - K)
+}
+
+pub fn encode(bytes: &[u8]) -> Vec<bool> {
+    let bits = bits_from_bytes(bytes);
+
+    bits.chunks(K)
+        .flat_map(|info| {
+            let mut padded = info.to_vec();
+            padded.resize(K, false);
+
+            let mut codeword = encode_block(&padded);
+            codeword.drain(info.len()..K);
+            codeword
+        })
+        .collect()
+}
+
+/// Normalised min-sum belief propagation decoding of a single codeword.
+/// Returns the hard decisions for the information bits.
+fn decode_block(checks: &[Vec<usize>], llr: &[f32]) -> Vec<bool> {
+    let mut msgs: Vec<Vec<f32>> = checks.iter().map(|c| vec![0.0; c.len()]).collect();
+    let mut posterior = llr.to_vec();
+
+    for _ in 0..MAX_ITER {
+        for (check, msg) in checks.iter().zip(msgs.iter_mut()) {
+            // Variable to check messages: the posterior with this check's own
+            // previous contribution removed.
+            let incoming: Vec<f32> = check
+                .iter()
+                .zip(msg.iter())
+                .map(|(v, m)| posterior[*v] - m)
+                .collect();
+
+            let sign = incoming.iter().filter(|x| **x < 0.0).count() % 2 == 1;
+            let (mut min1, mut min2, mut min_idx) = (f32::INFINITY, f32::INFINITY, 0);
+
+            for (i, x) in incoming.iter().enumerate() {
+                let x = x.abs();
+
+                if x < min1 {
+                    min2 = min1;
+                    min1 = x;
+                    min_idx = i;
+                } else if x < min2 {
+                    min2 = x;
+                }
+            }
+
+            for (i, (v, m)) in check.iter().zip(msg.iter_mut()).enumerate() {
+                let mag = if i == min_idx { min2 } else { min1 } * MIN_SUM_SCALE;
+                let negative = sign ^ (incoming[i] < 0.0);
+
+                *m = if negative { -mag } else { mag };
+                posterior[*v] = incoming[i] + *m;
+            }
+        }
+
+        let satisfied = checks
+            .iter()
+            .all(|c| c.iter().filter(|v| posterior[**v] < 0.0).count() % 2 == 0);
+
+        if satisfied {
+            break;
+        }
+    }
+
+    posterior[..K].iter().map(|x| *x < 0.0).collect()
+}
+
+/// Decode `n_bytes` bytes from the soft bits (log-likelihood ratios, positive
+/// for a `0`) of a block produced by `encode`.
+pub fn decode(soft: &[f32], n_bytes: usize) -> Vec<u8> {
+    assert_eq!(soft.len(), coded_len(n_bytes));
+
+    let checks = checks();
+    let mut info_bits = n_bytes * 8;
+    let mut bits = Vec::with_capacity(info_bits);
+
+    for codeword in soft.chunks(N) {
+        let n_info = info_bits.min(K);
+        let mut llr = codeword[..n_info].to_vec();
+
+        llr.resize(K, SHORTENED_LLR);
+        llr.extend(&codeword[n_info..]);
+
+        bits.extend(&decode_block(&checks, &llr)[..n_info]);
+        info_bits -= n_info;
+    }
+
+    bytes_from_bits(&bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rand_distr::{Distribution, Normal};
+
+    use super::{checks, coded_len, decode, encode, encode_block, K, N};
+
+    #[test]
+    fn codewords_satisfy_checks() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let checks = checks();
+
+        for _ in 0..10 {
+            let info: Vec<bool> = (0..K).map(|_| rng.gen()).collect();
+            let codeword = encode_block(&info);
+
+            assert_eq!(codeword.len(), N);
+            assert!(checks
+                .iter()
+                .all(|c| c.iter().filter(|v| codeword[**v]).count() % 2 == 0));
+        }
+    }
+
+    #[test]
+    fn shortened_round_trip() {
+        let bytes: Vec<u8> = (0..100).collect();
+        let coded = encode(&bytes);
+
+        assert_eq!(coded.len(), coded_len(bytes.len()));
+
+        let soft: Vec<f32> = coded.iter().map(|b| if *b { -1.0 } else { 1.0 }).collect();
+
+        assert_eq!(decode(&soft, bytes.len()), bytes);
+    }
+
+    #[test]
+    fn awgn_frame_error_rate() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let frames = 50;
+        let n_bytes = K / 8;
+
+        // Eb/N0 of 3dB with BPSK (QPSK is two independent BPSK channels).
+        let ebn0 = 10f32.powf(3.0 / 10.0);
+        let uncoded_noise = Normal::new(0.0, (1.0 / (2.0 * ebn0)).sqrt()).unwrap();
+        // Half rate code, so each coded bit carries half the energy.
+        let coded_var = 1.0 / ebn0;
+        let coded_noise = Normal::new(0.0, coded_var.sqrt()).unwrap();
+
+        let mut uncoded_errors = 0;
+        let mut coded_errors = 0;
+
+        for _ in 0..frames {
+            let bytes: Vec<u8> = (0..n_bytes).map(|_| rng.gen()).collect();
+
+            let uncoded: Vec<u8> = bytes
+                .iter()
+                .map(|byte| {
+                    (0..8).rev().fold(0, |acc, i| {
+                        let tx = if (byte >> i) & 1 == 1 { -1.0 } else { 1.0 };
+                        let rx: f32 = tx + uncoded_noise.sample(&mut rng);
+                        (acc << 1) | (rx < 0.0) as u8
+                    })
+                })
+                .collect();
+
+            if uncoded != bytes {
+                uncoded_errors += 1;
+            }
+
+            let soft: Vec<f32> = encode(&bytes)
+                .iter()
+                .map(|b| {
+                    let tx = if *b { -1.0 } else { 1.0 };
+                    2.0 * (tx + coded_noise.sample(&mut rng)) / coded_var
+                })
+                .collect();
+
+            if decode(&soft, n_bytes) != bytes {
+                coded_errors += 1;
+            }
+        }
+
+        assert!(uncoded_errors > frames / 2);
+        assert!(coded_errors < frames / 10);
+    }
+}
@@ -1,8 +1,25 @@
+pub mod agc;
+pub mod arq;
 pub mod carrier_sync;
+pub mod channel;
 pub mod clock_sync;
+pub mod constellation;
+mod crc;
+pub mod csma;
+pub mod equaliser;
+pub mod fec;
 pub mod frame;
+pub mod freq_sync;
+pub mod header;
+pub mod interleave;
+mod ldpc;
+pub mod link_quality;
+mod loop_filter;
+pub mod pulse_shape;
 pub mod qam;
-mod sym;
-mod sym_sync;
+mod reed_solomon;
+mod scrambler;
+pub mod sym;
+pub mod sym_sync;
 pub mod tap;
 pub mod test_tone;
@@ -0,0 +1,243 @@
+use std::fmt;
+
+use anyhow::Result;
+use futuresdr::{
+    async_trait::async_trait,
+    num_complex::Complex32,
+    runtime::{
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
+        StreamIoBuilder, WorkIo,
+    },
+};
+
+use crate::constellation::{Constellation, Modulation};
+use crate::qam::{LEVEL_ALPHA, SQUELCH};
+use crate::sym::MAGNITUDE;
+
+/// Number of symbols between each report of the running average.
+const REPORT_INTERVAL: usize = 1000;
+
+/// Time constant, in symbols, of the running average.
+const AVERAGE_ALPHA: f32 = 0.001;
+
+/// Error vector magnitude, as a fraction of the RMS magnitude of the
+/// constellation points, and the SNR it corresponds to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    pub evm: f32,
+    pub snr_db: f32,
+}
+
+impl Quality {
+    /// Quality given the mean power of the error vectors and of the
+    /// constellation points they were measured against.
+    fn from_power(error: f32, reference: f32) -> Self {
+        Quality {
+            evm: (error / reference).sqrt(),
+            snr_db: 10.0 * (reference / error).log10(),
+        }
+    }
+
+    pub fn to_pmt(self) -> Pmt {
+        Pmt::VecF32(vec![self.evm, self.snr_db])
+    }
+
+    pub fn from_pmt(p: &Pmt) -> Option<Self> {
+        match p {
+            Pmt::VecF32(v) if v.len() == 2 => Some(Quality {
+                evm: v[0],
+                snr_db: v[1],
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EVM {:.1}%, SNR {:.1} dB", self.evm * 100.0, self.snr_db)
+    }
+}
+
+/// Accumulates the error vectors of a block of symbols.
+#[derive(Debug, Default)]
+pub(crate) struct EvmMeter {
+    error: f32,
+    reference: f32,
+}
+
+impl EvmMeter {
+    /// Add a received symbol `x` whose nearest constellation point is `p`.
+    pub(crate) fn push(&mut self, x: Complex32, p: Complex32) {
+        self.error += (x - p).norm_sqr();
+        self.reference += p.norm_sqr();
+    }
+
+    pub(crate) fn quality(&self) -> Option<Quality> {
+        (self.reference > 0.0).then(|| Quality::from_power(self.error, self.reference))
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Measures the error vector magnitude and SNR of the symbols from the carrier
+/// sync against the nearest points of the modulation's decision
+/// constellation.  A running average is posted to the `quality` message
+/// output every `REPORT_INTERVAL` symbols, silence is squelched in the same
+/// way as by the demodulator and isn't counted.
+pub struct LinkQuality {
+    constellation: Box<dyn Constellation>,
+    /// Average magnitude of the last few samples.
+    level: f32,
+    /// Running averages of the power of the error vectors and of the
+    /// constellation points.
+    error: f32,
+    reference: f32,
+    /// Symbols measured since the last report.
+    count: usize,
+}
+
+impl LinkQuality {
+    pub fn new(modulation: Modulation) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("LinkQuality").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .build(),
+            MessageIoBuilder::new().add_output("quality").build(),
+            Self::create(modulation),
+        )
+    }
+
+    fn create(modulation: Modulation) -> Self {
+        LinkQuality {
+            constellation: modulation.decision_constellation(),
+            level: 0.0,
+            error: 0.0,
+            reference: MAGNITUDE * MAGNITUDE,
+            count: 0,
+        }
+    }
+
+    /// Measure a single symbol, returning the running average when a report
+    /// is due.
+    fn process(&mut self, x: Complex32) -> Option<Quality> {
+        self.level += (x.norm() - self.level) * LEVEL_ALPHA;
+
+        if self.level < SQUELCH * MAGNITUDE {
+            return None;
+        }
+
+        let p = self.constellation.decide(x);
+
+        self.error += ((x - p).norm_sqr() - self.error) * AVERAGE_ALPHA;
+        self.reference += (p.norm_sqr() - self.reference) * AVERAGE_ALPHA;
+        self.count += 1;
+
+        if self.count < REPORT_INTERVAL {
+            return None;
+        }
+
+        self.count = 0;
+
+        Some(Quality::from_power(self.error, self.reference))
+    }
+}
+
+#[async_trait]
+impl Kernel for LinkQuality {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let input = sio.input(0).slice::<Complex32>();
+        let reports: Vec<Quality> = input.iter().filter_map(|x| self.process(*x)).collect();
+
+        for q in reports {
+            mio.post(0, q.to_pmt()).await;
+        }
+
+        if sio.input(0).finished() {
+            io.finished = true;
+        }
+
+        sio.input(0).consume(input.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futuresdr::num_complex::Complex32;
+    use rand::{rngs::StdRng, SeedableRng};
+    use rand_distr::{Distribution, Normal};
+
+    use crate::{
+        constellation::Modulation,
+        sym::{Sym, MAGNITUDE},
+    };
+
+    use super::{EvmMeter, LinkQuality, Quality, REPORT_INTERVAL};
+
+    fn noisy_syms(sigma: f32, n: usize) -> Vec<Complex32> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let noise = Normal::new(0.0, sigma).unwrap();
+
+        Sym::random(n)
+            .iter()
+            .map(|sym| {
+                Complex32::from(sym)
+                    + Complex32::new(noise.sample(&mut rng), noise.sample(&mut rng))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn running_average() {
+        let sigma = 0.03;
+        let mut lq = LinkQuality::create(Modulation::Qpsk);
+        // A few symbols are lost to the squelch as the level rises.
+        let reports: Vec<Quality> = noisy_syms(sigma, 10 * REPORT_INTERVAL + 10)
+            .into_iter()
+            .filter_map(|x| lq.process(x))
+            .collect();
+
+        // Each component carries half the noise power.
+        let evm = (2.0 * sigma * sigma).sqrt() / MAGNITUDE;
+
+        assert_eq!(reports.len(), 10);
+        assert!((reports[9].evm - evm).abs() < 0.05 * evm);
+        assert!((reports[9].snr_db + 20.0 * evm.log10()).abs() < 0.5);
+    }
+
+    #[test]
+    fn silence_ignored() {
+        let mut lq = LinkQuality::create(Modulation::Qpsk);
+
+        for _ in 0..10 * REPORT_INTERVAL {
+            assert!(lq.process(Complex32::new(0.01, 0.0)).is_none());
+        }
+    }
+
+    #[test]
+    fn meter() {
+        let mut meter = EvmMeter::default();
+
+        assert!(meter.quality().is_none());
+
+        let p = Complex32::new(0.3, 0.3);
+        meter.push(p * 1.1, p);
+        meter.push(p * 0.9, p);
+
+        let q = meter.quality().unwrap();
+        assert!((q.evm - 0.1).abs() < 1e-5);
+        assert!((q.snr_db - 20.0).abs() < 1e-3);
+        assert_eq!(Quality::from_pmt(&q.to_pmt()), Some(q));
+    }
+}
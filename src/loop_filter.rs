@@ -0,0 +1,95 @@
+/// Proportional-integral loop filter of a second order tracking loop.  The
+/// integrator holds the loop's estimate of the rate of change (frequency
+/// offset or clock error) and the output is the correction to apply for the
+/// next update.
+pub struct LoopFilter {
+    damping: f32,
+    detector_gain: f32,
+    kp: f32,
+    ki: f32,
+    integrator: f32,
+    /// Largest magnitude of both the integrator and the output.
+    limit: f32,
+}
+
+impl LoopFilter {
+    /// `loop_bw` is the loop noise bandwidth normalised to the update rate,
+    /// `damping` the damping factor and `detector_gain` the slope of the
+    /// error detector around the lock point.
+    pub fn new(loop_bw: f32, damping: f32, detector_gain: f32, limit: f32) -> Self {
+        let mut ret = Self {
+            damping,
+            detector_gain,
+            kp: 0.0,
+            ki: 0.0,
+            integrator: 0.0,
+            limit,
+        };
+
+        ret.set_bandwidth(loop_bw);
+        ret
+    }
+
+    pub fn set_bandwidth(&mut self, loop_bw: f32) {
+        let theta = loop_bw / (self.damping + 1.0 / (4.0 * self.damping));
+        let d = 1.0 + 2.0 * self.damping * theta + theta * theta;
+
+        self.kp = 4.0 * self.damping * theta / d / self.detector_gain;
+        self.ki = 4.0 * theta * theta / d / self.detector_gain;
+    }
+
+    pub fn integrator(&self) -> f32 {
+        self.integrator
+    }
+
+    pub fn set_integrator(&mut self, x: f32) {
+        self.integrator = x.clamp(-self.limit, self.limit);
+    }
+
+    pub fn reset(&mut self) {
+        self.integrator = 0.0;
+    }
+
+    /// Feed the next error into the loop, returning the correction.
+    pub fn update(&mut self, err: f32) -> f32 {
+        self.set_integrator(self.integrator + self.ki * err);
+
+        (self.kp * err + self.integrator).clamp(-self.limit, self.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoopFilter;
+
+    #[test]
+    fn tracks_ramp() {
+        let mut lf = LoopFilter::new(0.05, 0.707, 1.0, 1.0);
+        let (mut phase, mut estimate, mut err) = (0.0, 0.0, 0.0);
+
+        // A second order loop follows a constant rate of change with no
+        // standing error.
+        for _ in 0..2000 {
+            err = phase - estimate;
+            estimate += lf.update(err);
+            phase += 0.01;
+        }
+
+        assert!(err.abs() < 1e-4);
+        assert!((lf.integrator() - 0.01).abs() < 1e-4);
+    }
+
+    #[test]
+    fn limits() {
+        let mut lf = LoopFilter::new(0.05, 0.707, 1.0, 0.1);
+
+        for _ in 0..1000 {
+            assert!(lf.update(100.0) <= 0.1);
+        }
+
+        assert_eq!(lf.integrator(), 0.1);
+
+        lf.reset();
+        assert_eq!(lf.integrator(), 0.0);
+    }
+}
@@ -0,0 +1,153 @@
+use std::f32::consts::PI;
+
+use futuresdr::{blocks::FirBuilder, num_complex::Complex32, runtime::Block};
+
+/// Shape of the pulse used to transmit each symbol.  Both ends of the link
+/// must agree on the pulse shape so that the receiver can apply the matched
+/// filter.
+#[derive(Debug, Clone, Copy, PartialEq, clap::Args)]
+pub struct PulseShape {
+    /// Use root-raised-cosine pulses rather than rectangular ones.
+    #[arg(long)]
+    pub rrc: bool,
+    /// Roll-off factor of the root-raised-cosine filter.
+    #[arg(long, default_value_t = 0.35)]
+    pub rolloff: f32,
+    /// Length of the root-raised-cosine filter, in symbols.
+    #[arg(long, default_value_t = 8)]
+    pub span: usize,
+}
+
+impl Default for PulseShape {
+    fn default() -> Self {
+        Self {
+            rrc: false,
+            rolloff: 0.35,
+            span: 8,
+        }
+    }
+}
+
+impl PulseShape {
+    /// Root-raised-cosine impulse response, normalised to unit energy.
+    fn rrc_taps(&self, sps: usize) -> Vec<f32> {
+        let beta = self.rolloff;
+        let half_len = (self.span * sps / 2) as isize;
+
+        let taps: Vec<f32> = (-half_len..=half_len)
+            .map(|n| {
+                let t = n as f32 / sps as f32;
+
+                if n == 0 {
+                    1.0 - beta + 4.0 * beta / PI
+                } else if beta > 0.0 && (1.0 - (4.0 * beta * t).powi(2)).abs() < 1e-6 {
+                    beta / 2f32.sqrt()
+                        * ((1.0 + 2.0 / PI) * (PI / (4.0 * beta)).sin()
+                            + (1.0 - 2.0 / PI) * (PI / (4.0 * beta)).cos())
+                } else {
+                    ((PI * t * (1.0 - beta)).sin() + 4.0 * beta * t * (PI * t * (1.0 + beta)).cos())
+                        / (PI * t * (1.0 - (4.0 * beta * t).powi(2)))
+                }
+            })
+            .collect();
+
+        let energy = taps.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+        taps.into_iter().map(|x| x / energy).collect()
+    }
+
+    /// Taps of the interpolating filter used by the modulator.  These are
+    /// scaled so the transmitted power matches that of rectangular pulses.
+    pub fn tx_taps(&self, sps: usize) -> Vec<f32> {
+        if self.rrc {
+            let gain = (sps as f32).sqrt();
+
+            self.rrc_taps(sps).into_iter().map(|x| x * gain).collect()
+        } else {
+            vec![1.0; sps]
+        }
+    }
+
+    /// Taps of the matched filter used by the receiver.  The combined
+    /// response of the transmit and receive filters has unit gain at the
+    /// symbol centre, so the constellation keeps its amplitude.
+    pub fn rx_taps(&self, sps: usize) -> Vec<f32> {
+        if self.rrc {
+            let gain = 1.0 / (sps as f32).sqrt();
+
+            self.rrc_taps(sps).into_iter().map(|x| x * gain).collect()
+        } else {
+            vec![1.0 / sps as f32; sps]
+        }
+    }
+
+    /// Receive side matched filter, to be placed before clock recovery.
+    pub fn matched_filter(&self, sps: usize) -> Block {
+        FirBuilder::new::<Complex32, Complex32, f32, _>(self.rx_taps(sps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PulseShape;
+
+    const SPS: usize = 10;
+
+    fn rrc() -> PulseShape {
+        PulseShape {
+            rrc: true,
+            ..PulseShape::default()
+        }
+    }
+
+    /// Convolve the transmit and receive filters to get the overall pulse.
+    fn raised_cosine(shape: PulseShape) -> Vec<f32> {
+        let tx = shape.tx_taps(SPS);
+        let rx = shape.rx_taps(SPS);
+        let mut ret = vec![0.0; tx.len() + rx.len() - 1];
+
+        for (i, t) in tx.iter().enumerate() {
+            for (j, r) in rx.iter().enumerate() {
+                ret[i + j] += t * r;
+            }
+        }
+
+        ret
+    }
+
+    #[test]
+    fn symmetric() {
+        let taps = rrc().tx_taps(SPS);
+
+        assert_eq!(taps.len(), 8 * SPS + 1);
+        assert!(taps
+            .iter()
+            .zip(taps.iter().rev())
+            .all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn unit_gain() {
+        for shape in [PulseShape::default(), rrc()] {
+            let rc = raised_cosine(shape);
+            let peak = rc.iter().cloned().fold(f32::MIN, f32::max);
+
+            assert!((peak - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn zero_isi() {
+        for rolloff in [0.2, 0.25, 0.35, 0.5] {
+            let rc = raised_cosine(PulseShape { rolloff, ..rrc() });
+            let centre = rc.len() / 2;
+
+            // The raised cosine pulse crosses zero at every other symbol
+            // centre.  Truncating the filter leaves a small residual.
+            for n in 1..4 {
+                assert!(rc[centre + n * SPS].abs() < 0.02);
+                assert!(rc[centre - n * SPS].abs() < 0.02);
+            }
+        }
+    }
+}
@@ -1,22 +1,29 @@
+use std::collections::VecDeque;
+
 use anyhow::Result;
 use futuresdr::{
     async_trait::async_trait,
-    blocks::Apply,
+    macros::message_handler,
     num_complex::Complex32,
     runtime::{
-        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, StreamIo,
+        Block, BlockMeta, BlockMetaBuilder, Kernel, MessageIo, MessageIoBuilder, Pmt, StreamIo,
         StreamIoBuilder, WorkIo,
     },
 };
 
-use crate::sym::{Sym, Symbol};
+use crate::constellation::Constellation;
+use crate::frame::{Coding, FrameDemapper};
+use crate::pulse_shape::PulseShape;
+use crate::sym::{SoftSym, Symbol, MAGNITUDE};
 
 pub struct QamMod {
     sps: u16,
+    taps: Vec<f32>,
+    history: VecDeque<Complex32>,
 }
 
 impl QamMod {
-    pub fn new(sps: u16) -> Block {
+    pub fn new(sps: u16, pulse: PulseShape) -> Block {
         Block::new(
             BlockMetaBuilder::new("QamMod").build(),
             StreamIoBuilder::new()
@@ -24,9 +31,60 @@ impl QamMod {
                 .add_output("out", std::mem::size_of::<Complex32>())
                 .build(),
             MessageIoBuilder::new().build(),
-            QamMod { sps },
+            Self::create(sps, pulse),
         )
     }
+
+    fn create(sps: u16, pulse: PulseShape) -> Self {
+        let taps = pulse.tx_taps(sps as usize);
+        let history = VecDeque::from(vec![
+            Complex32::new(0.0, 0.0);
+            taps.len().div_ceil(sps as usize)
+        ]);
+
+        QamMod { sps, taps, history }
+    }
+
+    /// Modulate a single symbol, writing `sps` samples to `out`.  Each output
+    /// sample is the sum of the pulses of all symbols that overlap it.
+    fn push_sym(&mut self, sym: &Symbol, out: &mut [Complex32]) {
+        self.history.pop_back();
+        self.history
+            .push_front(sym.unwrap_or(Complex32::new(0.0, 0.0)));
+
+        for (k, o) in out.iter_mut().enumerate() {
+            *o = self
+                .history
+                .iter()
+                .zip(self.taps.iter().skip(k).step_by(self.sps as usize))
+                .map(|(s, t)| s * t)
+                .sum();
+        }
+    }
+
+    /// Modulate `syms`, followed by enough idle symbols to flush the pulse
+    /// shaping filter.
+    #[cfg(test)]
+    pub(crate) fn modulate(
+        sps: u16,
+        pulse: PulseShape,
+        syms: &[crate::sym::Sym],
+    ) -> Vec<Complex32> {
+        let mut m = Self::create(sps, pulse);
+        let flush = m.history.len();
+        let mut out = vec![Complex32::new(0.0, 0.0); (syms.len() + flush) * sps as usize];
+
+        for (sym, chunk) in syms
+            .iter()
+            .map(|s| Some(Complex32::from(s)))
+            .chain(std::iter::repeat(None))
+            .zip(out.chunks_exact_mut(sps as usize))
+        {
+            m.push_sym(&sym, chunk);
+        }
+
+        out
+    }
 }
 
 #[async_trait]
@@ -38,68 +96,359 @@ impl Kernel for QamMod {
         _mio: &mut MessageIo<Self>,
         _b: &mut BlockMeta,
     ) -> Result<()> {
-        let input = sio.input(0);
-        let is = input.slice::<Symbol>();
-        let out_output = sio.output(0);
-        let os = out_output.slice::<Complex32>();
-        let mut syms_processed = 0;
-
-        if os.len() == 0 {
-            return Ok(());
+        let is = sio.input(0).slice::<Symbol>();
+        let os = sio.output(0).slice::<Complex32>();
+
+        let n = is.len().min(os.len() / self.sps as usize);
+
+        for (sym, chunk) in is
+            .iter()
+            .zip(os.chunks_exact_mut(self.sps as usize))
+            .take(n)
+        {
+            self.push_sym(sym, chunk);
         }
 
-        if sio.input(0).finished() {
+        if sio.input(0).finished() && n == is.len() {
             io.finished = true;
         }
 
-        for (i, chunk) in os.chunks_exact_mut(self.sps as usize).enumerate() {
-            if let Some(sym) = is.get(i) {
-                let samp = if let Some(samp) = sym {
-                    Complex32::from(samp)
-                } else {
-                    Complex32::new(0.0, 0.0)
-                };
+        sio.input(0).consume(n);
+        sio.output(0).produce(n * self.sps as usize);
 
-                for o in chunk.iter_mut() {
-                    *o = samp
-                }
+        Ok(())
+    }
+}
+
+/// Signals weaker than this fraction of the magnitude of the outermost
+/// constellation points are treated as silence by the demodulator.
+pub(crate) const SQUELCH: f32 = 0.25;
+
+/// Time constant, in symbols, of the signal level compared against the
+/// squelch.  Averaging over a few symbols stops the inner points of the
+/// denser constellations being mistaken for silence.
+pub(crate) const LEVEL_ALPHA: f32 = 0.125;
+
+/// Time constant, in symbols, of the gain and noise estimates.
+const NOISE_ALPHA: f32 = 0.01;
+
+/// Smallest noise variance reported, which stops a clean signal producing
+/// unbounded confidence.
+const MIN_NOISE_VAR: f32 = MAGNITUDE * MAGNITUDE / 2.0 * 1e-3;
+
+/// Demodulator for any of the supported modulations.  Produces hard
+/// decisions on the `out` port, made against the modulation's decision
+/// constellation as the rotation of the constellation is only known once the
+/// sync word has been found.
+///
+/// The `soft` port carries the frames found in the symbols: a marker for each
+/// sync word, then the log-likelihood ratio of each bit of the symbols of the
+/// header and data, and a marker with the quality of the frame after its
+/// last symbol.  The header is demapped with QPSK and the data with the
+/// modulation of the coding.  The ratios are scaled by the noise variance
+/// estimated from the distance between the samples and the decisions.
+///
+/// The length of the data is only known once the frame decoder has decoded
+/// the header, so after each header the demodulator waits for the decoder to
+/// post the number of data symbols to the `data` message input, or zero to
+/// drop the frame: the decoder's `data` output must be connected to it.
+pub struct QamDemod {
+    constellation: Box<dyn Constellation>,
+    demapper: FrameDemapper,
+    /// Soft output waiting for room in the output buffer.
+    soft: VecDeque<SoftSym>,
+    /// Average magnitude of the last few samples.
+    level: f32,
+    /// Estimated gain between the constellation and the samples.  The AGC
+    /// already scales the outermost points to the right magnitude, so this
+    /// starts at one and only takes out what is left over.
+    gain: f32,
+    /// Estimated noise variance of each component, relative to the
+    /// constellation.
+    noise_var: f32,
+}
+
+impl QamDemod {
+    /// `sync_threshold` is the largest number of bit errors allowed in the
+    /// sync word.
+    pub fn new(coding: Coding, sync_threshold: u32) -> Block {
+        Block::new(
+            BlockMetaBuilder::new("QamDemod").build(),
+            StreamIoBuilder::new()
+                .add_input("in", std::mem::size_of::<Complex32>())
+                .add_output("out", std::mem::size_of::<Symbol>())
+                .add_output("soft", std::mem::size_of::<SoftSym>())
+                .build(),
+            MessageIoBuilder::new()
+                .add_input("data", Self::data_handler)
+                .build(),
+            Self::create(coding, sync_threshold),
+        )
+    }
+
+    fn create(coding: Coding, sync_threshold: u32) -> Self {
+        QamDemod {
+            constellation: coding.modulation.decision_constellation(),
+            demapper: FrameDemapper::new(coding, sync_threshold),
+            soft: VecDeque::new(),
+            level: 0.0,
+            gain: 1.0,
+            noise_var: MAGNITUDE * MAGNITUDE,
+        }
+    }
+
+    fn demod(&mut self, x: Complex32) -> Symbol {
+        self.level += (x.norm() - self.level) * LEVEL_ALPHA;
+
+        if self.level < SQUELCH * MAGNITUDE {
+            return None;
+        }
+
+        let p = self.constellation.decide(x / self.gain);
+
+        self.gain += ((x * p.conj()).re / p.norm_sqr() - self.gain) * NOISE_ALPHA;
+
+        let x = x / self.gain;
+        let err = (x - p).norm_sqr() / 2.0;
+        self.noise_var += (err - self.noise_var) * NOISE_ALPHA;
+
+        self.demapper
+            .push_sym(x, self.noise_var.max(MIN_NOISE_VAR), &mut self.soft);
+
+        Some(p)
+    }
+
+    #[message_handler]
+    async fn data_handler(
+        &mut self,
+        _mio: &mut MessageIo<Self>,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Pmt::U32(len) = p {
+            self.demapper.start_data(len as usize);
+        }
+
+        Ok(Pmt::Null)
+    }
+}
+
+#[async_trait]
+impl Kernel for QamDemod {
+    async fn work(
+        &mut self,
+        io: &mut WorkIo,
+        sio: &mut StreamIo,
+        _mio: &mut MessageIo<Self>,
+        _b: &mut BlockMeta,
+    ) -> Result<()> {
+        let input = sio.input(0).slice::<Complex32>();
+        let hard = sio.output(0).slice::<Symbol>();
+        let soft = sio.output(1).slice::<SoftSym>();
+        let (mut n, mut m) = (0, 0);
+
+        // A symbol can produce more than one item of soft output, so the
+        // input is only consumed while they all fit.
+        loop {
+            while m < soft.len() {
+                let Some(s) = self.soft.pop_front() else {
+                    break;
+                };
+                soft[m] = s;
+                m += 1;
+            }
 
-                syms_processed = i;
+            if n == input.len().min(hard.len()) || !self.soft.is_empty() || self.demapper.waiting()
+            {
+                break;
             }
+
+            hard[n] = self.demod(input[n]);
+            n += 1;
+        }
+
+        if sio.input(0).finished() && n == input.len() && self.soft.is_empty() {
+            io.finished = true;
         }
 
-        sio.input(0).consume(syms_processed);
-        sio.output(0).produce(syms_processed * self.sps as usize);
+        sio.input(0).consume(n);
+        sio.output(0).produce(n);
+        sio.output(1).produce(m);
 
         Ok(())
     }
 }
 
-pub struct QamDemod;
+#[cfg(test)]
+mod tests {
+    use futuresdr::num_complex::Complex32;
 
-impl QamDemod {
-    pub fn new() -> Block {
-        Apply::new(move |x: &Complex32| {
-            let pi = std::f32::consts::PI;
-            let (mag, phase) = x.to_polar();
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rand_distr::{Distribution, Normal};
+
+    use crate::{
+        constellation::Modulation,
+        frame::{Coding, FrameEncoder},
+        pulse_shape::PulseShape,
+        sym::{SoftSym, Sym},
+        sym_sync::{DEFAULT_THRESHOLD, SYNC},
+    };
+
+    use super::{QamDemod, QamMod, MIN_NOISE_VAR};
+
+    const SPS: u16 = 10;
+
+    const SYMS: [Sym; 8] = [
+        Sym::A,
+        Sym::D,
+        Sym::B,
+        Sym::B,
+        Sym::C,
+        Sym::A,
+        Sym::D,
+        Sym::C,
+    ];
+
+    #[test]
+    fn rectangular() {
+        let out = QamMod::modulate(SPS, PulseShape::default(), &SYMS);
+
+        for (sym, chunk) in SYMS.iter().zip(out.chunks_exact(SPS as usize)) {
+            assert!(chunk.iter().all(|x| *x == Complex32::from(sym)));
+        }
+    }
 
-            if mag < 0.1 {
-                return None;
+    #[test]
+    fn rrc_matched_filter() {
+        let pulse = PulseShape {
+            rrc: true,
+            ..PulseShape::default()
+        };
+        let out = QamMod::modulate(SPS, pulse, &SYMS);
+        let rx_taps = pulse.rx_taps(SPS as usize);
+
+        // The combined filter delay is the length of one filter.
+        let delay = rx_taps.len() - 1;
+
+        for (i, sym) in SYMS.iter().enumerate() {
+            let centre = delay + i * SPS as usize;
+            let filtered: Complex32 = rx_taps
+                .iter()
+                .enumerate()
+                .map(|(j, t)| out[centre - j] * t)
+                .sum();
+
+            assert!((filtered - Complex32::from(sym)).norm() < 0.02);
+        }
+    }
+
+    #[test]
+    fn soft_demod() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let sigma: f32 = 0.007;
+        let noise_var = (sigma * sigma).max(MIN_NOISE_VAR);
+        let noise = Normal::new(0.0, sigma).unwrap();
+
+        for modulation in [
+            Modulation::Bpsk,
+            Modulation::Qpsk,
+            Modulation::Psk8,
+            Modulation::Qam16,
+            Modulation::Qam64,
+        ] {
+            let points = modulation.constellation().points().to_vec();
+            let coding = Coding {
+                modulation,
+                ..Coding::default()
+            };
+            let mut demod = QamDemod::create(coding, DEFAULT_THRESHOLD);
+
+            for i in 0..3000 {
+                let p = points[rng.gen_range(0..points.len())];
+                let x = (p + Complex32::new(noise.sample(&mut rng), noise.sample(&mut rng))) * 0.9;
+                let hard = demod.demod(x);
+
+                // The gain and noise estimates take a while to settle.
+                if i > 1000 {
+                    assert!((hard.unwrap() - p).norm() < 1e-6, "{modulation:?}");
+                    assert!((x / demod.gain - p).norm() < 5.0 * sigma, "{modulation:?}");
+                    assert!(
+                        (demod.noise_var.max(MIN_NOISE_VAR) - noise_var).abs() < 0.3 * noise_var
+                    );
+                }
             }
 
-            if phase > 0.0 {
-                if phase < pi / 2.0 {
-                    Some(Sym::A)
-                } else {
-                    Some(Sym::B)
+            // Silence is squelched after a few samples.
+            for _ in 0..10 {
+                demod.demod(Complex32::new(0.0, 0.0));
+            }
+            assert_eq!(demod.demod(Complex32::new(0.0, 0.0)), None);
+        }
+    }
+
+    #[test]
+    fn frame_llrs() {
+        for modulation in [
+            Modulation::Bpsk,
+            Modulation::Qpsk,
+            Modulation::Psk8,
+            Modulation::Qam16,
+            Modulation::Qam64,
+        ] {
+            let coding = Coding {
+                modulation,
+                ..Coding::default()
+            };
+            let syms = FrameEncoder::frame_syms(coding, &[0xde, 0xad, 0xbe, 0xef]);
+            let qpsk = Modulation::Qpsk.constellation();
+            let constellation = modulation.constellation();
+            let mut demod = QamDemod::create(coding, DEFAULT_THRESHOLD);
+            let mut header = true;
+            let mut expected = Vec::new();
+
+            // The constellation is turned by a quarter turn, which is undone
+            // once the sync word has been found.  Each symbol after the sync
+            // words is demapped with the constellation it was sent with.
+            for (i, p) in syms.iter().enumerate() {
+                demod.demod(p * Complex32::i());
+
+                if i >= 2 * SYNC.len() {
+                    let c = if header { &qpsk } else { &constellation };
+                    let noise_var = demod.noise_var.max(MIN_NOISE_VAR);
+
+                    expected.push(SoftSym::from_llrs(&c.demap(*p, noise_var)));
                 }
-            } else {
-                if phase > -pi / 2.0 {
-                    Some(Sym::C)
-                } else {
-                    Some(Sym::D)
+
+                if demod.demapper.waiting() {
+                    header = false;
+                    demod.demapper.start_data(syms.len() - i - 1);
                 }
             }
-        })
+
+            let soft: Vec<SoftSym> = demod.soft.drain(..).collect();
+            let n = soft.len();
+
+            assert!(!header);
+            assert_eq!(soft[n - expected.len() - 2], SoftSym::Sync);
+            assert!(matches!(soft[n - 1], SoftSym::End(_)));
+
+            for (s, e) in soft[n - expected.len() - 1..n - 1].iter().zip(expected) {
+                let (
+                    SoftSym::Bits { llr, len },
+                    SoftSym::Bits {
+                        llr: e_llr,
+                        len: e_len,
+                    },
+                ) = (s, e)
+                else {
+                    panic!("{s:?} isn't the soft bits of a symbol");
+                };
+
+                assert_eq!(*len, e_len, "{modulation:?}");
+                for (a, b) in llr.iter().zip(e_llr) {
+                    assert!((a - b).abs() <= 1e-3 * b.abs().max(1.0), "{modulation:?}");
+                }
+            }
+        }
     }
 }
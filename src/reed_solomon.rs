@@ -0,0 +1,301 @@
+/// Reed-Solomon RS(255, 223) code over GF(2^8), able to correct up to 16 byte
+/// errors per codeword.  Messages longer than one codeword are split into
+/// blocks, the final block is shortened rather than padded.
+pub const RS_N: usize = 255;
+pub const RS_K: usize = 223;
+const NSYM: usize = RS_N - RS_K;
+
+const PRIM: u16 = 0x11d;
+
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const TABLES: Tables = make_tables();
+
+const fn make_tables() -> Tables {
+    let mut exp = [0; 512];
+    let mut log = [0; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= PRIM;
+        }
+
+        i += 1;
+    }
+
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+
+    Tables { exp, log }
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+
+    TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    assert_ne!(b, 0);
+
+    if a == 0 {
+        return 0;
+    }
+
+    TABLES.exp[TABLES.log[a as usize] as usize + 255 - TABLES.log[b as usize] as usize]
+}
+
+/// α^n
+fn pow(n: usize) -> u8 {
+    TABLES.exp[n % 255]
+}
+
+/// Evaluate a polynomial stored lowest order coefficient first.
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, c| mul(acc, x) ^ c)
+}
+
+/// g(x) = (x - α^0)(x - α^1)...(x - α^(NSYM - 1)), highest order coefficient
+/// first to suit the long division in `encode_block`.
+fn generator() -> [u8; NSYM + 1] {
+    let mut g = [0; NSYM + 1];
+    g[0] = 1;
+
+    for i in 0..NSYM {
+        let root = pow(i);
+
+        for j in (1..=i + 1).rev() {
+            g[j] ^= mul(g[j - 1], root);
+        }
+    }
+
+    g
+}
+
+/// Number of bytes produced by `encode` for a message of `n` bytes.
+pub fn encoded_len(n: usize) -> usize {
+    n + n.div_ceil(RS_K) * NSYM
+}
+
+fn encode_block(data: &[u8]) -> Vec<u8> {
+    let g = generator();
+    let mut rem = [0u8; NSYM];
+
+    for byte in data {
+        let coef = byte ^ rem[0];
+
+        rem.rotate_left(1);
+        rem[NSYM - 1] = 0;
+
+        rem.iter_mut()
+            .zip(&g[1..])
+            .for_each(|(r, g)| *r ^= mul(*g, coef));
+    }
+
+    let mut ret = data.to_vec();
+    ret.extend(rem);
+    ret
+}
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    data.chunks(RS_K).flat_map(encode_block).collect()
+}
+
+/// Correct a single (possibly shortened) codeword in place, returning the
+/// number of corrected bytes or `None` if the codeword is uncorrectable.
+fn decode_block(block: &mut [u8]) -> Option<usize> {
+    let n = block.len();
+
+    // The first byte of the block is the highest order coefficient.
+    let position = |j: usize| n - 1 - j;
+
+    let syndromes: Vec<u8> = (0..NSYM)
+        .map(|i| block.iter().fold(0, |acc, c| mul(acc, pow(i)) ^ c))
+        .collect();
+
+    if syndromes.iter().all(|s| *s == 0) {
+        return Some(0);
+    }
+
+    // Berlekamp-Massey to find the error locator polynomial.
+    let mut lambda = vec![1u8];
+    let mut prev = vec![1u8];
+    let mut l = 0;
+    let mut m = 1;
+    let mut b = 1;
+
+    for i in 0..NSYM {
+        let d = (1..=l).fold(syndromes[i], |acc, j| {
+            acc ^ mul(*lambda.get(j).unwrap_or(&0), syndromes[i - j])
+        });
+
+        if d == 0 {
+            m += 1;
+            continue;
+        }
+
+        let coef = div(d, b);
+        let mut next = lambda.clone();
+        next.resize(next.len().max(prev.len() + m), 0);
+        prev.iter()
+            .enumerate()
+            .for_each(|(j, p)| next[j + m] ^= mul(coef, *p));
+
+        if 2 * l <= i {
+            prev = lambda;
+            l = i + 1 - l;
+            b = d;
+            m = 1;
+        } else {
+            m += 1;
+        }
+
+        lambda = next;
+    }
+
+    if l > NSYM / 2 {
+        return None;
+    }
+
+    // Chien search for the error positions.
+    let errors: Vec<usize> = (0..n)
+        .filter(|j| eval(&lambda, pow(255 - position(*j) % 255)) == 0)
+        .collect();
+
+    if errors.len() != l {
+        return None;
+    }
+
+    // Forney's algorithm for the error magnitudes.
+    let mut omega = vec![0u8; NSYM];
+    for (i, s) in syndromes.iter().enumerate() {
+        for (j, l) in lambda.iter().enumerate().take(NSYM - i) {
+            omega[i + j] ^= mul(*s, *l);
+        }
+    }
+
+    let lambda_prime: Vec<u8> = lambda
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, c)| if i % 2 == 1 { *c } else { 0 })
+        .collect();
+
+    for j in errors.iter() {
+        let x = pow(position(*j));
+        let x_inv = pow(255 - position(*j) % 255);
+        let denom = eval(&lambda_prime, x_inv);
+
+        if denom == 0 {
+            return None;
+        }
+
+        block[*j] ^= mul(x, div(eval(&omega, x_inv), denom));
+    }
+
+    Some(errors.len())
+}
+
+/// Decode a message produced by `encode`.  Returns the corrected message and
+/// the number of bytes that were corrected.  Blocks with too many errors to
+/// correct are passed through unaltered.
+pub fn decode(coded: &[u8]) -> (Vec<u8>, usize) {
+    let mut corrections = 0;
+    let mut ret = Vec::with_capacity(coded.len());
+
+    for block in coded.chunks(RS_N) {
+        let mut block = block.to_vec();
+
+        if let Some(n) = decode_block(&mut block) {
+            corrections += n;
+        }
+
+        ret.extend(&block[..block.len() - NSYM]);
+    }
+
+    (ret, corrections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, encoded_len, RS_K, RS_N};
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(encoded_len(RS_K), RS_N);
+        assert_eq!(encoded_len(10), 42);
+        assert_eq!(encoded_len(RS_K + 1), RS_N + 33);
+        assert_eq!(encode(&message(500)).len(), encoded_len(500));
+    }
+
+    #[test]
+    fn no_errors() {
+        let msg = message(RS_K);
+
+        assert_eq!(decode(&encode(&msg)), (msg, 0));
+    }
+
+    #[test]
+    fn max_errors() {
+        let msg = message(RS_K);
+        let mut coded = encode(&msg);
+
+        (0..16).for_each(|i| coded[i * 15] ^= 0xa5);
+
+        assert_eq!(decode(&coded), (msg, 16));
+    }
+
+    #[test]
+    fn errors_in_parity() {
+        let msg = message(20);
+        let mut coded = encode(&msg);
+
+        coded[25] ^= 0xff;
+        coded[51] ^= 0x01;
+
+        assert_eq!(decode(&coded), (msg, 2));
+    }
+
+    #[test]
+    fn burst_error_multi_block() {
+        let msg = message(600);
+        let mut coded = encode(&msg);
+
+        // A burst of 16 bytes in each of the three blocks.
+        (100..116).for_each(|i| coded[i] = 0);
+        (300..316).for_each(|i| coded[i] = 0);
+        (coded.len() - 16..coded.len()).for_each(|i| coded[i] = 0);
+
+        let (decoded, corrections) = decode(&coded);
+
+        assert_eq!(decoded, msg);
+        assert!(corrections <= 48);
+    }
+
+    #[test]
+    fn too_many_errors() {
+        let msg = message(RS_K);
+        let mut coded = encode(&msg);
+
+        (0..20).for_each(|i| coded[i] ^= 0xa5);
+
+        assert_ne!(decode(&coded).0, msg);
+    }
+}
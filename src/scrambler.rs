@@ -0,0 +1,76 @@
+const SEED: u8 = 0x7f;
+
+/// Additive scrambler using the IEEE 802.11 x^7 + x^4 + 1 polynomial.  XORing
+/// the frame with the pseudo-random sequence breaks up long runs of identical
+/// symbols, which would otherwise produce spectral lines and leave the clock
+/// recovery with no symbol transitions to work with.
+pub struct Scrambler {
+    state: u8,
+}
+
+impl Scrambler {
+    pub fn new() -> Self {
+        Self { state: SEED }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = SEED;
+    }
+
+    fn next_bit(&mut self) -> bool {
+        let bit = ((self.state >> 6) ^ (self.state >> 3)) & 1;
+
+        self.state = ((self.state << 1) | bit) & 0x7f;
+
+        bit == 1
+    }
+
+    /// Scramble a group of coded bits in place.
+    pub fn scramble(&mut self, bits: &mut [bool]) {
+        bits.iter_mut().for_each(|b| *b ^= self.next_bit());
+    }
+
+    /// Descramble the soft bits of a symbol in place, the sign of a soft bit
+    /// is flipped wherever the scrambler flipped the bit.
+    pub fn descramble(&mut self, soft: &mut [f32]) {
+        soft.iter_mut().for_each(|x| {
+            if self.next_bit() {
+                *x = -*x;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scrambler;
+
+    #[test]
+    fn maximal_length() {
+        let mut s = Scrambler::new();
+        let seq: Vec<bool> = (0..254).map(|_| s.next_bit()).collect();
+
+        assert_eq!(seq[..127], seq[127..]);
+        assert!((1..127).all(|p| seq[..127] != seq[p..p + 127]));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut tx = Scrambler::new();
+        let mut rx = Scrambler::new();
+
+        for n in (1..7).cycle().take(100) {
+            let bits: Vec<bool> = (0..n).map(|i| (i * n) % 3 == 0).collect();
+            let mut scrambled = bits.clone();
+            tx.scramble(&mut scrambled);
+
+            let mut soft: Vec<f32> = scrambled
+                .iter()
+                .map(|b| if *b { -1.0 } else { 1.0 })
+                .collect();
+            rx.descramble(&mut soft);
+
+            assert!(soft.iter().zip(&bits).all(|(s, b)| (*s < 0.0) == *b));
+        }
+    }
+}
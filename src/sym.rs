@@ -1,5 +1,7 @@
 use futuresdr::num_complex::Complex32;
 
+use crate::link_quality::Quality;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Sym {
     A,
@@ -39,6 +41,28 @@ impl Sym {
         ret
     }
 
+    pub fn from_bits(msb: bool, lsb: bool) -> Sym {
+        Self::convert_nibble((msb as u8) << 1 | lsb as u8)
+    }
+
+    /// `n` pseudo-random symbols, the same on every run.
+    #[cfg(test)]
+    pub(crate) fn random(n: usize) -> Vec<Sym> {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(1);
+
+        (0..n)
+            .map(|_| Self::from_bits(rng.gen(), rng.gen()))
+            .collect()
+    }
+
+    /// Hard decision on the quadrant of a point, used to find the sync words
+    /// whatever the modulation of the data.
+    pub fn from_point(x: Complex32) -> Sym {
+        Self::from_bits(x.im < 0.0, x.re < 0.0)
+    }
+
     pub fn inc(&self) -> Self {
         match self {
             Sym::A => Sym::C,
@@ -67,6 +91,17 @@ impl Sym {
         }
     }
 
+    /// The symbol mirrored in the real axis.  Swapping I and Q mirrors the
+    /// whole constellation, which is the same as this up to a rotation.
+    pub fn conj(&self) -> Self {
+        match self {
+            Sym::A => Sym::C,
+            Sym::B => Sym::D,
+            Sym::C => Sym::A,
+            Sym::D => Sym::B,
+        }
+    }
+
     pub fn sub(&self, n: usize) -> Self {
         match n & 0x3 {
             0 => *self,
@@ -80,6 +115,8 @@ impl Sym {
 
 #[cfg(test)]
 mod tests {
+    use futuresdr::num_complex::Complex32;
+
     use super::Sym;
 
     #[test]
@@ -129,10 +166,34 @@ mod tests {
         );
         assert_eq!(x.iter().map(|x| x.add(8)).collect::<Vec<_>>(), x);
     }
+
+    #[test]
+    fn from_point() {
+        for sym in [Sym::A, Sym::B, Sym::C, Sym::D] {
+            let x = Complex32::from(&sym);
+
+            assert_eq!(Sym::from_point(x), sym);
+            assert_eq!(Sym::from_point(x * 0.1), sym);
+        }
+    }
+
+    #[test]
+    fn conj() {
+        for sym in [Sym::A, Sym::B, Sym::C, Sym::D] {
+            let x = Complex32::from(&sym);
+
+            assert_eq!(Sym::from_point(x.conj()), sym.conj());
+            assert_eq!(sym.conj().conj(), sym);
+        }
+    }
 }
 
 const N: f32 = 0.3;
 
+/// Magnitude of every point of the QPSK constellation, and of the outermost
+/// points of the others.
+pub const MAGNITUDE: f32 = N * std::f32::consts::SQRT_2;
+
 impl From<&Sym> for Complex32 {
     fn from(value: &Sym) -> Self {
         let _pi = std::f32::consts::PI;
@@ -146,4 +207,33 @@ impl From<&Sym> for Complex32 {
     }
 }
 
-pub type Symbol = Option<Sym>;
+/// A constellation point, or `None` for silence.
+pub type Symbol = Option<Complex32>;
+
+/// Largest number of bits carried by a symbol, by 64QAM.
+pub const MAX_BITS: usize = 6;
+
+/// Soft output of the demodulator, which marks out the frames it finds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoftSym {
+    /// A sync word, which the header of a frame follows.
+    Sync,
+    /// The log-likelihood ratio of each bit of a symbol of the header or data,
+    /// most significant first and scaled by the estimated noise variance.
+    /// Positive values represent a `0` bit.  Only the first `len` are used.
+    Bits { llr: [f32; MAX_BITS], len: usize },
+    /// The end of the data of a frame, with the quality of its symbols.
+    End(Quality),
+}
+
+impl SoftSym {
+    pub fn from_llrs(llrs: &[f32]) -> Self {
+        let mut llr = [0.0; MAX_BITS];
+        llr[..llrs.len()].copy_from_slice(llrs);
+
+        SoftSym::Bits {
+            llr,
+            len: llrs.len(),
+        }
+    }
+}
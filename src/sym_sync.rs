@@ -1,5 +1,4 @@
 use crate::sym::Sym;
-use std::collections::BTreeMap;
 
 pub const SYNC: [Sym; 16] = [
     Sym::A,
@@ -20,26 +19,87 @@ pub const SYNC: [Sym; 16] = [
     Sym::C,
 ];
 
+/// Bits in the sync word.
+const SYNC_BITS: u32 = 2 * SYNC.len() as u32;
+
+/// Default largest number of bit errors in a sync word that is still
+/// detected.  It keeps the false alarm rate on random data below one in a
+/// million symbols.
+pub const DEFAULT_THRESHOLD: u32 = 2;
+
+/// Largest number of bit errors in a sync word that can be allowed without
+/// ambiguity.
+pub const MAX_THRESHOLD: u32 = 3;
+
+/// Probability that the last 16 symbols of random data fall within
+/// `threshold` bit errors of one of the rotations of the sync word or its
+/// mirror image, which is the rate of false alarms per symbol.
+pub fn false_alarm_rate(threshold: u32) -> f64 {
+    let mut n_choose_k = 1.0;
+    let mut within = 0.0;
+
+    for k in 0..=threshold.min(SYNC_BITS) {
+        within += n_choose_k;
+        n_choose_k *= (SYNC_BITS - k) as f64 / (k + 1) as f64;
+    }
+
+    8.0 * within / 2f64.powi(SYNC_BITS as i32)
+}
+
+/// A sync word found by `SymSync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Detection {
+    /// Whether the constellation has been mirrored, by swapping I and Q.
+    pub mirrored: bool,
+    /// Number of quarter turns the constellation has been rotated by, after
+    /// undoing any mirroring by taking the complex conjugate.
+    pub rotation: usize,
+    /// Number of bits that differ from the sync word.
+    pub distance: u32,
+}
+
+impl Detection {
+    /// Probability that the detection wasn't a false alarm caused by random
+    /// data matching the sync word at least this well.
+    pub fn confidence(&self) -> f64 {
+        1.0 - false_alarm_rate(self.distance)
+    }
+}
+
+/// Searches the symbol stream for the sync word in any of its four
+/// rotations, and their mirror images, allowing up to `threshold` bit errors.
+/// The eight patterns differ from each other in at least 16 bits, and from
+/// any other alignment of two back to back sync words in at least 8, so
+/// thresholds of up to `MAX_THRESHOLD` are unambiguous.
 pub struct SymSync {
-    rotations: BTreeMap<u32, usize>,
+    /// The sync word in each rotation, indexed by whether it is mirrored and
+    /// then the number of quarter turns.
+    rotations: [[u32; 4]; 2],
+    threshold: u32,
     n: u32,
 }
 
 impl SymSync {
-    pub fn new() -> Self {
-        let mut rotations = BTreeMap::new();
+    pub fn new(threshold: u32) -> Self {
+        assert!(threshold <= MAX_THRESHOLD);
 
-        (0..4).for_each(|n| {
-            let mut sync_pattern: u32 = 0;
+        let mut rotations = [[0; 4]; 2];
 
-            SYNC.iter().for_each(|s| {
-                Self::push_nibble(&mut sync_pattern, s.add(n));
-            });
+        for (mirrored, patterns) in rotations.iter_mut().enumerate() {
+            for (n, sync_pattern) in patterns.iter_mut().enumerate() {
+                for s in SYNC.iter() {
+                    let s = s.add(n);
 
-            rotations.insert(sync_pattern, n);
-        });
+                    Self::push_nibble(sync_pattern, if mirrored == 1 { s.conj() } else { s });
+                }
+            }
+        }
 
-        Self { rotations, n: 0 }
+        Self {
+            rotations,
+            threshold,
+            n: 0,
+        }
     }
 
     fn push_nibble(n: &mut u32, s: Sym) {
@@ -47,10 +107,25 @@ impl SymSync {
         *n |= u8::from(s) as u32;
     }
 
-    pub fn push_sym(&mut self, s: Sym) -> Option<usize> {
+    pub fn push_sym(&mut self, s: Sym) -> Option<Detection> {
         Self::push_nibble(&mut self.n, s);
 
-        self.rotations.get(&self.n).copied()
+        self.rotations
+            .iter()
+            .enumerate()
+            .flat_map(|(mirrored, patterns)| {
+                patterns
+                    .iter()
+                    .enumerate()
+                    .map(move |(rotation, p)| (mirrored == 1, rotation, p))
+            })
+            .map(|(mirrored, rotation, p)| Detection {
+                mirrored,
+                rotation,
+                distance: (self.n ^ p).count_ones(),
+            })
+            .min_by_key(|d| d.distance)
+            .filter(|d| d.distance <= self.threshold)
     }
 }
 
@@ -58,9 +133,16 @@ impl SymSync {
 mod tests {
     use crate::sym::Sym;
 
-    use super::{SymSync, SYNC};
+    use super::{false_alarm_rate, Detection, SymSync, DEFAULT_THRESHOLD, MAX_THRESHOLD, SYNC};
 
-    fn run(mut s: SymSync, mut sym_transform: impl FnMut(Sym) -> Sym) -> Option<usize> {
+    fn run(s: SymSync, sym_transform: impl FnMut(Sym) -> Sym) -> Option<usize> {
+        run_detection(s, sym_transform).map(|d| d.rotation)
+    }
+
+    fn run_detection(
+        mut s: SymSync,
+        mut sym_transform: impl FnMut(Sym) -> Sym,
+    ) -> Option<Detection> {
         let mut it = SYNC.iter().peekable();
 
         while let Some(sym) = it.next() {
@@ -78,27 +160,39 @@ mod tests {
 
     #[test]
     fn sync_no_rot() {
-        assert!(matches!(run(SymSync::new(), |s| s), Some(0)));
+        assert!(matches!(
+            run(SymSync::new(DEFAULT_THRESHOLD), |s| s),
+            Some(0)
+        ));
     }
 
     #[test]
     fn sync_rot_1() {
-        assert!(matches!(run(SymSync::new(), |s| s.add(1)), Some(1)));
+        assert!(matches!(
+            run(SymSync::new(DEFAULT_THRESHOLD), |s| s.add(1)),
+            Some(1)
+        ));
     }
 
     #[test]
     fn sync_rot_2() {
-        assert!(matches!(run(SymSync::new(), |s| s.add(2)), Some(2)));
+        assert!(matches!(
+            run(SymSync::new(DEFAULT_THRESHOLD), |s| s.add(2)),
+            Some(2)
+        ));
     }
 
     #[test]
     fn sync_rot_3() {
-        assert!(matches!(run(SymSync::new(), |s| s.add(3)), Some(3)));
+        assert!(matches!(
+            run(SymSync::new(DEFAULT_THRESHOLD), |s| s.add(3)),
+            Some(3)
+        ));
     }
 
     #[test]
     fn with_preamble() {
-        let mut s = SymSync::new();
+        let mut s = SymSync::new(DEFAULT_THRESHOLD);
 
         s.push_sym(Sym::A);
         s.push_sym(Sym::B);
@@ -107,4 +201,111 @@ mod tests {
 
         assert!(matches!(run(s, |s| s.add(3)), Some(3)));
     }
+
+    #[test]
+    fn sync_mirrored() {
+        for n in 0..4 {
+            assert_eq!(
+                run_detection(SymSync::new(DEFAULT_THRESHOLD), |s| s.add(n).conj()),
+                Some(Detection {
+                    mirrored: true,
+                    rotation: n,
+                    distance: 0
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn symbol_errors() {
+        // Adjacent symbols differ in a single bit.
+        let mut i = 0;
+        let d = run_detection(SymSync::new(DEFAULT_THRESHOLD), |s| {
+            i += 1;
+            if i == 3 || i == 11 {
+                s.add(2).add(1)
+            } else {
+                s.add(2)
+            }
+        });
+
+        assert_eq!(
+            d,
+            Some(Detection {
+                mirrored: false,
+                rotation: 2,
+                distance: 2
+            })
+        );
+
+        let mut i = 0;
+        let d = run_detection(SymSync::new(DEFAULT_THRESHOLD), |s| {
+            i += 1;
+            if i == 3 || i == 11 || i == 16 {
+                s.add(1)
+            } else {
+                s
+            }
+        });
+
+        assert_eq!(d, None);
+    }
+
+    #[test]
+    fn unambiguous() {
+        let mut s = SymSync::new(0);
+        let rotations = s.rotations.concat();
+
+        for a in 0..8 {
+            for b in 0..8 {
+                if a != b {
+                    assert!((rotations[a] ^ rotations[b]).count_ones() >= 16);
+                }
+            }
+        }
+
+        // Push two sync words back to back, all but the last alignment of
+        // which are more than twice the largest threshold from any pattern.
+        let mut distances = Vec::new();
+        for sym in SYNC.iter().chain(SYNC.iter()).skip(1) {
+            s.push_sym(*sym);
+            distances.push(rotations.iter().map(|p| (s.n ^ p).count_ones()).min());
+        }
+
+        assert!(distances[15..30]
+            .iter()
+            .all(|d| d.unwrap() > 2 * MAX_THRESHOLD));
+        assert_eq!(distances[30], Some(0));
+    }
+
+    #[test]
+    fn default_threshold() {
+        assert!(false_alarm_rate(DEFAULT_THRESHOLD) < 1e-6);
+        assert!(false_alarm_rate(DEFAULT_THRESHOLD + 1) > 1e-6);
+
+        let d = Detection {
+            mirrored: false,
+            rotation: 0,
+            distance: 0,
+        };
+        assert!((d.confidence() - (1.0 - 8.0 / 2f64.powi(32))).abs() < 1e-15);
+    }
+
+    #[test]
+    fn false_alarms() {
+        let threshold = MAX_THRESHOLD;
+        let n = 2_000_000;
+        let mut s = SymSync::new(threshold);
+        let mut alarms = 0;
+
+        for sym in Sym::random(n) {
+            if s.push_sym(sym).is_some() {
+                alarms += 1;
+            }
+        }
+
+        // Within three standard deviations of the expected count.
+        let expected = false_alarm_rate(threshold) * n as f64;
+        assert!((alarms as f64 - expected).abs() < 3.0 * expected.sqrt());
+    }
 }
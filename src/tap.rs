@@ -1,6 +1,6 @@
 use std::{io::ErrorKind, sync::Arc};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use futuresdr::{
     async_trait::async_trait,
     macros::message_handler,
@@ -11,16 +11,46 @@ use futuresdr::{
 };
 use tun_tap::Iface;
 
+use crate::arq::Mac;
+use crate::frame::MAX_FRAME_SZ;
+
+/// Default MTU of the interface, the largest payload of the Ethernet frames
+/// read from it.
+const MTU: usize = 1500;
+
+/// Size of the Ethernet header at the start of every frame read from the
+/// interface.
+const ETH_HEADER_LEN: usize = 14;
+
+/// Ethernet address of the interface `name`.
+fn mac_address(name: &str) -> Result<Mac> {
+    let path = format!("/sys/class/net/{}/address", name);
+    let address =
+        std::fs::read_to_string(&path).with_context(|| format!("Could not read {}", path))?;
+    let mac = address
+        .trim()
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()
+        .and_then(|b| Mac::try_from(b).ok());
+
+    mac.with_context(|| format!("Invalid Ethernet address {:?} for {}", address.trim(), name))
+}
+
 pub struct Tap {
     tap: Arc<async_io::Async<Iface>>,
 }
 
 impl Tap {
-    pub fn new(name: &str, mode: tun_tap::Mode) -> Result<Block> {
+    /// Creates the interface, returning the block along with the Ethernet
+    /// address the interface was given.
+    pub fn new(name: &str, mode: tun_tap::Mode) -> Result<(Block, Mac)> {
         let tap = Iface::without_packet_info(name, mode)?;
+        let mac = mac_address(tap.name())?;
         let tap = Arc::new(async_io::Async::new(tap)?);
 
-        Ok(Block::new(
+        let block = Block::new(
             BlockMetaBuilder::new("Tap").build(),
             StreamIoBuilder::new().build(),
             MessageIoBuilder::new()
@@ -28,7 +58,9 @@ impl Tap {
                 .add_output("out")
                 .build(),
             Tap { tap },
-        ))
+        );
+
+        Ok((block, mac))
     }
 
     #[message_handler]
@@ -64,10 +96,15 @@ impl Kernel for Tap {
             return Ok(());
         }
 
-        let mut buf = vec![0; 1500];
+        let mut buf = vec![0; MTU + ETH_HEADER_LEN];
 
         loop {
             match self.tap.as_ref().as_ref().recv(&mut buf) {
+                Ok(len) if len > MAX_FRAME_SZ => eprintln!(
+                    "Dropping {} byte packet, too large to send.  Is the MTU of the TAP device {} or less?",
+                    len,
+                    MAX_FRAME_SZ - ETH_HEADER_LEN
+                ),
                 Ok(len) => mio.post(0, Pmt::Blob(buf[..len].to_vec())).await,
                 Err(e) if e.kind() == ErrorKind::WouldBlock => break,
                 _ => bail!("Error reading from tap interface"),